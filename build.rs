@@ -11,9 +11,19 @@ fn main() {
     let bpf_source = "probe.bpf.c";
     let bpf_output = PathBuf::from(&out_dir).join("probe.bpf.o");
 
+    // bpf_tracing.h needs the target architecture to pick the PT_REGS_PARM* registers
+    let target_arch = match env::var("CARGO_CFG_TARGET_ARCH").as_deref() {
+        Ok("x86_64") => "x86",
+        Ok("aarch64") => "arm64",
+        Ok("riscv64") => "riscv",
+        Ok(arch) => panic!("Unsupported target architecture for eBPF: {}", arch),
+        Err(_) => panic!("CARGO_CFG_TARGET_ARCH environment variable not set"),
+    };
+    let target_arch_define = format!("-D__TARGET_ARCH_{}", target_arch);
+
     // Compile the eBPF program
     let status = Command::new("clang")
-        .args(&[
+        .args([
             "-O2",
             "-g", // Enable debug symbols (needed for BTF)
             "-target",
@@ -22,7 +32,9 @@ fn main() {
             bpf_source,
             "-o",
             bpf_output.to_str().unwrap(),
-            "-D__KERNEL__",      // Required for kernel eBPF
+            // No __KERNEL__: bpf_tracing.h then reads the UAPI `struct pt_regs`
+            // layout that linux/ptrace.h defines for user space
+            &target_arch_define,
             "-D__BPF_TRACING__", // Required for tracing BPF programs
             "-Wall",
             "-Werror",
@@ -1,64 +1,478 @@
 #include <linux/bpf.h>
-#include <bpf/bpf_helpers.h>
 #include <linux/ptrace.h>
+#include <bpf/bpf_helpers.h>
+#include <bpf/bpf_tracing.h>
+
+#define MAX_ARGS 4
+#define ARG_DATA_SIZE 64
+
+#define ARG_KIND_INT 0
+#define ARG_KIND_STR 1
+#define ARG_KIND_BYTES 2
+
+#define ARG_FLAG_FIELD (1 << 0) // Read at register value + offset
+#define ARG_FLAG_DEREF (1 << 1) // Follow the pointer stored at that address
+
+#define FUNCTION_FLAG_AGGREGATE (1 << 0) // Count calls in call_stats, no events
+#define FUNCTION_FLAG_COUNT (1 << 1)     // Also count sampled calls in call_stats
+#define CALL_FLAG_UNSAMPLED (1u << 31)   // Set on call_entry.flags only
 
+#define ERROR_WHEN_NONE 0
+#define ERROR_WHEN_NEGATIVE 1
+#define ERROR_WHEN_NONZERO 2
+#define ERROR_WHEN_ZERO 3
+#define ERROR_WHEN_RESULT_ERR 4 // Discriminant 1 in the low byte
+
+#define LATENCY_BUCKETS 64 // log2 nanosecond buckets
+
+#define FILTER_TGID (1 << 0)   // Trace processes in allowed_tgids
+#define FILTER_CGROUP (1 << 1) // Trace tasks in or below a cgroup in allowed_cgroups
+#define MAX_CGROUP_LEVELS 16   // cgroup ancestors checked against allowed_cgroups
+
+#define MAX_CALL_DEPTH 64 // Deeper calls are not traced
+#define MAX_UNWIND 8      // Frames searched for a matching entry on return
+
+// Shared by every probe. Resized by user space before loading
 struct {
     __uint(type, BPF_MAP_TYPE_RINGBUF);
-    __uint(max_entries, 4096);
+    __uint(max_entries, 16 << 20);
 } events SEC(".maps");
 
+// Calls whose event did not fit in the ring buffer, read by user space
+struct {
+    __uint(type, BPF_MAP_TYPE_PERCPU_ARRAY);
+    __uint(max_entries, 1);
+    __type(key, __u32);
+    __type(value, __u64);
+} dropped_events SEC(".maps");
+
+struct arg_spec {
+    __u8 reg;   // Argument register index, 0-5
+    __u8 kind;  // ARG_KIND_*
+    __u8 size;  // Integer width, or buffer length for strings and bytes
+    __u8 flags; // ARG_FLAG_*
+    __u32 offset;
+};
+
+// When a return value counts as an error, for calls counted in call_stats
+struct return_rule {
+    __u8 error_when; // ERROR_WHEN_*
+    __u8 size;       // Width of the return value in bytes
+    __u8 _pad[2];
+};
+
+// Per-function settings written by user space, keyed by function id (cookie)
+struct function_config {
+    __u32 arg_count;
+    __u32 flags; // FUNCTION_FLAG_*
+    struct arg_spec args[MAX_ARGS];
+    struct return_rule return_rule;
+};
+
+struct {
+    __uint(type, BPF_MAP_TYPE_HASH);
+    __uint(max_entries, 10240);
+    __type(key, __u64);
+    __type(value, struct function_config);
+} function_configs SEC(".maps");
+
+struct call_entry {
+    __u64 timestamp_start;
+    __u64 function_id;
+    __u64 trace_id[2];
+    __u64 span_id;
+    __u64 parent_span_id; // 0 for the outermost traced call on the thread
+    __u32 arg_errors;     // Bit i set when argument i could not be read
+    __u32 flags;          // Copied from the function's config, plus CALL_FLAG_*
+    __u8 args[MAX_ARGS][ARG_DATA_SIZE];
+};
+
+// One frame of a thread's stack of in-flight traced calls
+struct frame_key {
+    __u64 pid_tgid;
+    __u32 depth;
+    __u32 _pad;
+};
+
+// Number of in-flight traced calls per thread, keyed by pid_tgid
+struct {
+    __uint(type, BPF_MAP_TYPE_LRU_HASH);
+    __uint(max_entries, 10240);
+    __type(key, __u64);
+    __type(value, __u32);
+} call_depths SEC(".maps");
+
+// In-flight calls
+struct {
+    __uint(type, BPF_MAP_TYPE_LRU_HASH);
+    __uint(max_entries, 65536);
+    __type(key, struct frame_key);
+    __type(value, struct call_entry);
+} entries SEC(".maps");
+
+// Aggregated calls of functions in FUNCTION_FLAG_AGGREGATE or
+// FUNCTION_FLAG_COUNT mode. Counters only grow; user space reports the
+// difference between reads
+struct call_stats {
+    __u64 count;
+    __u64 errors;
+    __u64 total_ns;
+    __u64 latency[LATENCY_BUCKETS]; // Bucket i counts durations in [2^i, 2^(i+1)) ns
+};
+
+struct {
+    __uint(type, BPF_MAP_TYPE_PERCPU_HASH);
+    __uint(max_entries, 10240);
+    __type(key, __u64);
+    __type(value, struct call_stats);
+} call_stats SEC(".maps");
+
+// Initial value of new call_stats entries
+static struct call_stats zero_stats;
+
+// Head sampling, keyed by function id. A call is sampled when a random u32 is
+// below the threshold; functions without an entry are always sampled.
+struct {
+    __uint(type, BPF_MAP_TYPE_HASH);
+    __uint(max_entries, 10240);
+    __type(key, __u64);
+    __type(value, __u64);
+} sample_thresholds SEC(".maps");
+
+// Which of the allowlists below apply (FILTER_*), at key 0. Every task is
+// traced when it is zero; otherwise a task matching any allowlist is.
+struct {
+    __uint(type, BPF_MAP_TYPE_ARRAY);
+    __uint(max_entries, 1);
+    __type(key, __u32);
+    __type(value, __u32);
+} filter_flags SEC(".maps");
+
+struct {
+    __uint(type, BPF_MAP_TYPE_HASH);
+    __uint(max_entries, 1024);
+    __type(key, __u32);
+    __type(value, __u8);
+} allowed_tgids SEC(".maps");
+
+// cgroup v2 ids
+struct {
+    __uint(type, BPF_MAP_TYPE_HASH);
+    __uint(max_entries, 64);
+    __type(key, __u64);
+    __type(value, __u8);
+} allowed_cgroups SEC(".maps");
+
+// call_entry is too large for the BPF stack, so it is built here
+struct {
+    __uint(type, BPF_MAP_TYPE_PERCPU_ARRAY);
+    __uint(max_entries, 1);
+    __type(key, __u32);
+    __type(value, struct call_entry);
+} entry_scratch SEC(".maps");
+
 struct event_t {
     __u64 timestamp_start;
     __u64 timestamp_end;
+    __u64 function_id; // BPF cookie set by user space at attach time
+    __u64 retval;      // Raw return register
+    __u64 trace_id[2];
+    __u64 span_id;
+    __u64 parent_span_id;
     __u32 pid;
+    __u32 tid;
+    __u32 cpu; // CPU the call returned on
+    __u32 arg_errors;
+    __u64 cgroup_id; // cgroup v2 id of the calling task
     char comm[16];
+    __u8 args[MAX_ARGS][ARG_DATA_SIZE];
 };
 
-static __always_inline void submit_enter_event(struct pt_regs *ctx) {
-    struct event_t event = {};
-    
-    event.timestamp_start = bpf_ktime_get_ns();
-    event.timestamp_end = 0;
-    event.pid = bpf_get_current_pid_tgid() >> 32;
-    bpf_get_current_comm(&event.comm, sizeof(event.comm));
-    
-    void *ringbuf_event = bpf_ringbuf_reserve(&events, sizeof(event), 0);
-    if (!ringbuf_event)
+static __always_inline __u64 read_arg_register(struct pt_regs *ctx, __u8 reg) {
+    switch (reg) {
+    case 0: return PT_REGS_PARM1(ctx);
+    case 1: return PT_REGS_PARM2(ctx);
+    case 2: return PT_REGS_PARM3(ctx);
+    case 3: return PT_REGS_PARM4(ctx);
+    case 4: return PT_REGS_PARM5(ctx);
+    case 5: return PT_REGS_PARM6(ctx);
+    default: return 0;
+    }
+}
+
+static __always_inline int capture_arg(struct pt_regs *ctx, const struct arg_spec *spec, __u8 *out) {
+    __u64 value = read_arg_register(ctx, spec->reg);
+    __u32 size = spec->size;
+
+    if (size == 0 || size > ARG_DATA_SIZE)
+        return -1;
+
+    if (spec->flags & ARG_FLAG_FIELD) {
+        value += spec->offset;
+        if (spec->flags & ARG_FLAG_DEREF &&
+            bpf_probe_read_user(&value, sizeof(value), (void *)value) < 0)
+            return -1;
+    }
+
+    switch (spec->kind) {
+    case ARG_KIND_INT:
+        if (!(spec->flags & ARG_FLAG_FIELD)) {
+            __builtin_memcpy(out, &value, sizeof(value));
+            return 0;
+        }
+        return bpf_probe_read_user(out, size, (void *)value) < 0 ? -1 : 0;
+    case ARG_KIND_STR:
+        return bpf_probe_read_user_str(out, size, (void *)value) < 0 ? -1 : 0;
+    case ARG_KIND_BYTES:
+        return bpf_probe_read_user(out, size, (void *)value) < 0 ? -1 : 0;
+    default:
+        return -1;
+    }
+}
+
+static __always_inline __u32 log2_u64(__u64 value) {
+    __u32 log = 0;
+    if (value >> 32) { value >>= 32; log += 32; }
+    if (value >> 16) { value >>= 16; log += 16; }
+    if (value >> 8) { value >>= 8; log += 8; }
+    if (value >> 4) { value >>= 4; log += 4; }
+    if (value >> 2) { value >>= 2; log += 2; }
+    if (value >> 1) log += 1;
+    return log;
+}
+
+// Mirrors ReturnSpec::is_error in src/capture.rs
+static __always_inline int is_error_return(const struct return_rule *rule, __u64 retval) {
+    __u32 bits = rule->size * 8;
+    __u64 value = bits && bits < 64 ? retval & ((1ull << bits) - 1) : retval;
+    switch (rule->error_when) {
+    case ERROR_WHEN_NEGATIVE:
+        return bits && (value >> (bits - 1)) & 1;
+    case ERROR_WHEN_NONZERO:
+        return value != 0;
+    case ERROR_WHEN_ZERO:
+        return value == 0;
+    case ERROR_WHEN_RESULT_ERR:
+        return (retval & 0xff) == 1;
+    default:
+        return 0;
+    }
+}
+
+static __always_inline void aggregate_call(__u64 function_id, __u64 duration_ns, int error) {
+    struct call_stats *stats = bpf_map_lookup_elem(&call_stats, &function_id);
+    if (!stats) {
+        bpf_map_update_elem(&call_stats, &function_id, &zero_stats, BPF_NOEXIST);
+        stats = bpf_map_lookup_elem(&call_stats, &function_id);
+        if (!stats)
+            return;
+    }
+
+    // Per-CPU values, so no atomics are needed
+    stats->count++;
+    if (error)
+        stats->errors++;
+    stats->total_ns += duration_ns;
+    __u32 bucket = log2_u64(duration_ns);
+    if (bucket < LATENCY_BUCKETS)
+        stats->latency[bucket]++;
+}
+
+static __always_inline __u64 random_id(void) {
+    __u64 id = ((__u64)bpf_get_prandom_u32() << 32) | bpf_get_prandom_u32();
+    return id ? id : 1; // Zero is an invalid trace or span id
+}
+
+static __always_inline int is_traced_task(__u64 pid_tgid) {
+    __u32 zero = 0;
+    __u32 *flags = bpf_map_lookup_elem(&filter_flags, &zero);
+    if (!flags || !*flags)
+        return 1;
+
+    __u32 tgid = pid_tgid >> 32;
+    if (*flags & FILTER_TGID && bpf_map_lookup_elem(&allowed_tgids, &tgid))
+        return 1;
+
+    if (*flags & FILTER_CGROUP) {
+        // Level 0 is the root cgroup; pods and containers nest below it
+        for (int level = 1; level <= MAX_CGROUP_LEVELS; level++) {
+            __u64 cgroup_id = bpf_get_current_ancestor_cgroup_id(level);
+            if (!cgroup_id)
+                break; // Above the task's own cgroup
+            if (bpf_map_lookup_elem(&allowed_cgroups, &cgroup_id))
+                return 1;
+        }
+    }
+    return 0;
+}
+
+static __always_inline void record_entry(struct pt_regs *ctx) {
+    __u64 pid_tgid = bpf_get_current_pid_tgid();
+    __u64 function_id = bpf_get_attach_cookie(ctx);
+    __u32 zero = 0;
+
+    // Filtered calls never get a frame, so their returns are ignored too
+    if (!is_traced_task(pid_tgid))
         return;
-    
-    __builtin_memcpy(ringbuf_event, &event, sizeof(event));
-    bpf_ringbuf_submit(ringbuf_event, 0);
+
+    __u32 depth = 0;
+    __u32 *current_depth = bpf_map_lookup_elem(&call_depths, &pid_tgid);
+    if (current_depth)
+        depth = *current_depth;
+
+    // Past the limit only the depth is tracked, so returns stay balanced
+    __u32 next_depth = depth + 1;
+    bpf_map_update_elem(&call_depths, &pid_tgid, &next_depth, BPF_ANY);
+    if (depth >= MAX_CALL_DEPTH)
+        return;
+
+    struct call_entry *entry = bpf_map_lookup_elem(&entry_scratch, &zero);
+    if (!entry)
+        return;
+    __builtin_memset(entry, 0, sizeof(*entry));
+    entry->timestamp_start = bpf_ktime_get_ns();
+    entry->function_id = function_id;
+    entry->span_id = random_id();
+
+    // Nested in another traced call on this thread: join its trace
+    struct frame_key parent_key = { .pid_tgid = pid_tgid, .depth = depth - 1 };
+    struct call_entry *parent = depth ? bpf_map_lookup_elem(&entries, &parent_key) : NULL;
+    if (parent) {
+        entry->trace_id[0] = parent->trace_id[0];
+        entry->trace_id[1] = parent->trace_id[1];
+        entry->parent_span_id = parent->span_id;
+    } else {
+        entry->trace_id[0] = random_id();
+        entry->trace_id[1] = random_id();
+    }
+
+    // Calls nested in an unsampled call are not sampled either, so kept
+    // traces are complete from their root down
+    __u64 *threshold = bpf_map_lookup_elem(&sample_thresholds, &function_id);
+    if ((parent && parent->flags & CALL_FLAG_UNSAMPLED) ||
+        (threshold && (__u64)bpf_get_prandom_u32() >= *threshold))
+        entry->flags |= CALL_FLAG_UNSAMPLED;
+
+    struct function_config *config = bpf_map_lookup_elem(&function_configs, &function_id);
+    if (config)
+        entry->flags |= config->flags;
+    if (config && config->flags & FUNCTION_FLAG_AGGREGATE) {
+        // Produces no span, so calls nested in it attach to its parent. An
+        // outermost aggregated call keeps its trace id, so the calls nested
+        // in it are roots of one trace rather than of one each
+        entry->span_id = entry->parent_span_id;
+    } else if (config && !(entry->flags & CALL_FLAG_UNSAMPLED)) {
+        for (int i = 0; i < MAX_ARGS; i++) {
+            if (i >= config->arg_count)
+                break;
+            if (capture_arg(ctx, &config->args[i], entry->args[i]) < 0)
+                entry->arg_errors |= 1 << i;
+        }
+    }
+
+    struct frame_key key = { .pid_tgid = pid_tgid, .depth = depth };
+    bpf_map_update_elem(&entries, &key, entry, BPF_ANY);
 }
 
-static __always_inline void submit_exit_event(struct pt_regs *ctx) {
-    struct event_t event = {};
-    
-    event.timestamp_start = 0;
-    event.timestamp_end = bpf_ktime_get_ns();
-    event.pid = bpf_get_current_pid_tgid() >> 32;
-    bpf_get_current_comm(&event.comm, sizeof(event.comm));
-    
-    void *ringbuf_event = bpf_ringbuf_reserve(&events, sizeof(event), 0);
-    if (!ringbuf_event)
+static __always_inline void submit_call_event(struct pt_regs *ctx) {
+    __u64 timestamp_end = bpf_ktime_get_ns();
+    __u64 pid_tgid = bpf_get_current_pid_tgid();
+    __u64 function_id = bpf_get_attach_cookie(ctx);
+
+    __u32 *current_depth = bpf_map_lookup_elem(&call_depths, &pid_tgid);
+    if (!current_depth || *current_depth == 0)
+        return; // Entry was missed (e.g. probe attached mid-call)
+    __u32 depth = *current_depth;
+
+    if (depth > MAX_CALL_DEPTH) {
+        // Returning from an untraced call beyond the depth limit
+        __u32 new_depth = depth - 1;
+        bpf_map_update_elem(&call_depths, &pid_tgid, &new_depth, BPF_ANY);
         return;
-    
-    __builtin_memcpy(ringbuf_event, &event, sizeof(event));
-    bpf_ringbuf_submit(ringbuf_event, 0);
+    }
+
+    // Normally the top frame belongs to this function. Frames above the
+    // matching one lost their return (e.g. a probe was detached mid-call, or
+    // the stack was unwound by longjmp or a panic) and are dropped. With no
+    // matching frame, the entry of this call was missed and the stack is
+    // left alone.
+    struct frame_key key = { .pid_tgid = pid_tgid };
+    struct call_entry *entry = NULL;
+    for (int i = 0; i < MAX_UNWIND; i++) {
+        if (i >= depth)
+            return;
+        key.depth = depth - 1 - i;
+        entry = bpf_map_lookup_elem(&entries, &key);
+        if (entry && entry->function_id == function_id)
+            break;
+        entry = NULL;
+    }
+    if (!entry)
+        return;
+
+    for (int i = 1; i < MAX_UNWIND; i++) {
+        struct frame_key stale_key = { .pid_tgid = pid_tgid, .depth = key.depth + i };
+        if (stale_key.depth >= depth)
+            break;
+        bpf_map_delete_elem(&entries, &stale_key);
+    }
+    __u32 new_depth = key.depth;
+    if (new_depth == 0)
+        bpf_map_delete_elem(&call_depths, &pid_tgid);
+    else
+        bpf_map_update_elem(&call_depths, &pid_tgid, &new_depth, BPF_ANY);
+
+    // Every call is counted, sampled or not, so call metrics stay complete
+    if (entry->flags & (FUNCTION_FLAG_AGGREGATE | FUNCTION_FLAG_COUNT)) {
+        struct function_config *config = bpf_map_lookup_elem(&function_configs, &function_id);
+        int error = config && is_error_return(&config->return_rule, PT_REGS_RC(ctx));
+        aggregate_call(function_id, timestamp_end - entry->timestamp_start, error);
+    }
+    if (entry->flags & (FUNCTION_FLAG_AGGREGATE | CALL_FLAG_UNSAMPLED)) {
+        bpf_map_delete_elem(&entries, &key);
+        return;
+    }
+
+    struct event_t *event = bpf_ringbuf_reserve(&events, sizeof(*event), 0);
+    if (!event) {
+        __u32 zero = 0;
+        __u64 *dropped = bpf_map_lookup_elem(&dropped_events, &zero);
+        if (dropped)
+            (*dropped)++;
+        bpf_map_delete_elem(&entries, &key);
+        return;
+    }
+
+    event->timestamp_start = entry->timestamp_start;
+    event->timestamp_end = timestamp_end;
+    event->function_id = function_id;
+    event->retval = PT_REGS_RC(ctx);
+    event->trace_id[0] = entry->trace_id[0];
+    event->trace_id[1] = entry->trace_id[1];
+    event->span_id = entry->span_id;
+    event->parent_span_id = entry->parent_span_id;
+    event->pid = pid_tgid >> 32;
+    event->tid = (__u32)pid_tgid;
+    event->cpu = bpf_get_smp_processor_id();
+    event->cgroup_id = bpf_get_current_cgroup_id();
+    event->arg_errors = entry->arg_errors;
+    bpf_get_current_comm(&event->comm, sizeof(event->comm));
+    __builtin_memcpy(event->args, entry->args, sizeof(event->args));
+
+    bpf_map_delete_elem(&entries, &key);
+    bpf_ringbuf_submit(event, 0);
 }
 
 SEC("uprobe/trace_enter")
 int trace_enter(struct pt_regs *ctx) {
-    bpf_printk("Enter probe triggered\n"); 
-    //submit_enter_event(ctx);
+    record_entry(ctx);
     return 0;
 }
 
 SEC("uretprobe/trace_exit")
 int trace_exit(struct pt_regs *ctx) {
-    bpf_printk("Exit probe triggered\n");
-
-    //submit_exit_event(ctx);
+    submit_call_event(ctx);
     return 0;
 }
 
-char LICENSE[] SEC("license") = "GPL";
\ No newline at end of file
+char LICENSE[] SEC("license") = "GPL";
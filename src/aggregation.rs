@@ -0,0 +1,190 @@
+use crate::metrics::FunctionMetrics;
+use crate::registry::FunctionRegistry;
+use anyhow::Result;
+use libbpf_rs::{MapCore, MapFlags, MapHandle};
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Number of log2 latency buckets. Must match `LATENCY_BUCKETS` in probe.bpf.c.
+pub const LATENCY_BUCKETS: usize = 64;
+/// Size of `struct call_stats`.
+const CALL_STATS_SIZE: usize = 24 + LATENCY_BUCKETS * 8;
+
+/// Calls of one counted function, summed over all CPUs. Mirrors
+/// `struct call_stats` in probe.bpf.c.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CallStats {
+    pub count: u64,
+    /// Calls whose return value matched the function's error rule.
+    pub errors: u64,
+    pub total_ns: u64,
+    /// Bucket `i` counts calls that took `[2^i, 2^(i+1))` nanoseconds.
+    pub latency: [u64; LATENCY_BUCKETS],
+}
+
+impl CallStats {
+    /// Sums the per-CPU values of one `call_stats` entry.
+    pub fn from_per_cpu(values: &[Vec<u8>]) -> Result<Self> {
+        let mut stats = Self {
+            count: 0,
+            errors: 0,
+            total_ns: 0,
+            latency: [0; LATENCY_BUCKETS],
+        };
+        for value in values {
+            if value.len() < CALL_STATS_SIZE {
+                return Err(anyhow::anyhow!("Invalid call stats size"));
+            }
+            let word = |i: usize| u64::from_ne_bytes(value[i * 8..i * 8 + 8].try_into().unwrap());
+            stats.count += word(0);
+            stats.errors += word(1);
+            stats.total_ns += word(2);
+            for (bucket, count) in stats.latency.iter_mut().enumerate() {
+                *count += word(3 + bucket);
+            }
+        }
+        Ok(stats)
+    }
+
+    /// Calls counted since `previous`, an earlier read of the same entry.
+    /// An entry that went back (it was deleted and recreated) counts from
+    /// zero.
+    pub fn since(&self, previous: &CallStats) -> CallStats {
+        let went_back = self.count < previous.count
+            || self.errors < previous.errors
+            || self.total_ns < previous.total_ns
+            || self
+                .latency
+                .iter()
+                .zip(&previous.latency)
+                .any(|(current, previous)| current < previous);
+        if went_back {
+            return self.clone();
+        }
+        let mut latency = [0; LATENCY_BUCKETS];
+        for (bucket, delta) in latency.iter_mut().enumerate() {
+            *delta = self.latency[bucket] - previous.latency[bucket];
+        }
+        CallStats {
+            count: self.count - previous.count,
+            errors: self.errors - previous.errors,
+            total_ns: self.total_ns - previous.total_ns,
+            latency,
+        }
+    }
+}
+
+/// Reads the `call_stats` map every `interval` and publishes the calls
+/// counted since the previous read as metrics. The BPF program only ever
+/// adds to the counters, so no call is lost between reads. Runs until a map
+/// operation fails.
+pub async fn run(
+    stats_map: MapHandle,
+    registry: FunctionRegistry,
+    metrics: Arc<FunctionMetrics>,
+    interval: Duration,
+) -> Result<()> {
+    let mut ticker = tokio::time::interval(interval);
+    ticker.tick().await; // The first tick completes immediately
+    let mut previous: HashMap<u64, CallStats> = HashMap::new();
+
+    loop {
+        ticker.tick().await;
+
+        let mut current = HashMap::new();
+        let keys: Vec<Vec<u8>> = stats_map.keys().collect();
+        for key in keys {
+            let Some(values) = stats_map.lookup_percpu(&key, MapFlags::ANY)? else {
+                continue;
+            };
+            let stats = CallStats::from_per_cpu(&values)?;
+            let function_id = u64::from_ne_bytes(key.as_slice().try_into()?);
+            let delta = match previous.get(&function_id) {
+                Some(previous) => stats.since(previous),
+                None => stats.clone(),
+            };
+
+            match registry.get(function_id) {
+                Some(function) => {
+                    if delta.count > 0 {
+                        metrics.record_aggregated(&function, &delta);
+                    }
+                    current.insert(function_id, stats);
+                }
+                None => {
+                    if delta.count > 0 {
+                        eprintln!(
+                            "⚠️ Dropping {} aggregated calls of unknown function {}",
+                            delta.count, function_id
+                        );
+                    }
+                    stats_map.delete(&key)?;
+                }
+            }
+        }
+        // Entries of removed functions are forgotten with them
+        previous = current;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn per_cpu_value(count: u64, errors: u64, total_ns: u64, buckets: &[(usize, u64)]) -> Vec<u8> {
+        let mut words = vec![0u64; 3 + LATENCY_BUCKETS];
+        words[0] = count;
+        words[1] = errors;
+        words[2] = total_ns;
+        for &(bucket, bucket_count) in buckets {
+            words[3 + bucket] = bucket_count;
+        }
+        words.iter().flat_map(|word| word.to_ne_bytes()).collect()
+    }
+
+    #[test]
+    fn test_per_cpu_values_are_summed() {
+        let stats = CallStats::from_per_cpu(&[
+            per_cpu_value(3, 1, 3000, &[(9, 2), (10, 1)]),
+            per_cpu_value(1, 1, 70_000, &[(16, 1)]),
+            per_cpu_value(0, 0, 0, &[]),
+        ])
+        .unwrap();
+
+        assert_eq!(stats.count, 4);
+        assert_eq!(stats.errors, 2);
+        assert_eq!(stats.total_ns, 73_000);
+        assert_eq!(stats.latency[9], 2);
+        assert_eq!(stats.latency[10], 1);
+        assert_eq!(stats.latency[16], 1);
+        assert_eq!(stats.latency.iter().sum::<u64>(), 4);
+    }
+
+    #[test]
+    fn test_delta_since_previous_read() {
+        let previous =
+            CallStats::from_per_cpu(&[per_cpu_value(2, 1, 2000, &[(9, 1), (10, 1)])]).unwrap();
+        let current = CallStats::from_per_cpu(&[
+            per_cpu_value(3, 1, 2500, &[(9, 2), (10, 1)]),
+            per_cpu_value(1, 1, 70_000, &[(16, 1)]),
+        ])
+        .unwrap();
+
+        let delta = current.since(&previous);
+        assert_eq!(delta.count, 2);
+        assert_eq!(delta.errors, 1);
+        assert_eq!(delta.total_ns, 70_500);
+        assert_eq!(delta.latency[9], 1);
+        assert_eq!(delta.latency[10], 0);
+        assert_eq!(delta.latency[16], 1);
+
+        // Recreated entry: everything in it is new
+        assert_eq!(previous.since(&current), previous);
+    }
+
+    #[test]
+    fn test_truncated_value_is_rejected() {
+        assert!(CallStats::from_per_cpu(&[vec![0u8; CALL_STATS_SIZE - 8]]).is_err());
+    }
+}
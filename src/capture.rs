@@ -0,0 +1,511 @@
+use opentelemetry::{KeyValue, Value};
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use std::io;
+use std::sync::LazyLock;
+
+/// Maximum number of arguments captured per call. Must match `MAX_ARGS` in probe.bpf.c.
+pub const MAX_ARGS: usize = 4;
+/// Bytes reserved per captured argument. Must match `ARG_DATA_SIZE` in probe.bpf.c.
+pub const ARG_DATA_SIZE: usize = 64;
+/// Number of argument registers the BPF program can read (`PT_REGS_PARM1..6`).
+const MAX_REGISTERS: u8 = 6;
+
+// `struct arg_spec` encoding, see probe.bpf.c
+const ARG_KIND_INT: u8 = 0;
+const ARG_KIND_STR: u8 = 1;
+const ARG_KIND_BYTES: u8 = 2;
+const ARG_FLAG_FIELD: u8 = 1 << 0;
+const ARG_FLAG_DEREF: u8 = 1 << 1;
+
+/// `function_config.flags`: report calls through the `call_stats` map only.
+pub const FUNCTION_FLAG_AGGREGATE: u32 = 1 << 0;
+/// `function_config.flags`: also count every call in `call_stats`, whether or
+/// not it is sampled.
+pub const FUNCTION_FLAG_COUNT: u32 = 1 << 1;
+
+// `struct return_rule.error_when` encoding, see probe.bpf.c
+const ERROR_WHEN_NONE: u8 = 0;
+const ERROR_WHEN_NEGATIVE: u8 = 1;
+const ERROR_WHEN_NONZERO: u8 = 2;
+const ERROR_WHEN_ZERO: u8 = 3;
+const ERROR_WHEN_RESULT_ERR: u8 = 4;
+
+// argN, argN+OFF or *(argN+OFF), then `: type` and `as "name"`
+static ARG_SPEC_REGEX: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(
+        r#"^\s*(?:(?P<deref>\*)\(\s*)?arg(?P<reg>\d+)(?:\s*\+\s*(?P<offset>0x[0-9a-fA-F]+|\d+))?\s*(?P<close>\))?\s*:\s*(?P<type>[a-z0-9]+)(?:\((?P<len>\d+)\))?\s+as\s+"(?P<name>[^"]+)"\s*$"#,
+    )
+    .unwrap()
+});
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ArgType {
+    Int {
+        size: u8,
+        signed: bool,
+    },
+    Bool,
+    /// A raw address, reported in hex.
+    Ptr,
+    /// NUL-terminated string of at most the given length.
+    Str(u8),
+    /// Fixed-size buffer, reported in hex.
+    Bytes(u8),
+}
+
+/// How to capture one function argument, parsed from specs such as
+/// `arg0: u64 as "user_id"`, `arg1: str(64) as "path"`,
+/// `arg2+16: u32 as "req.len"` (field at an offset into the struct `arg2`
+/// points to) or `*(arg2+8): str(32) as "req.host"` (string behind a pointer
+/// field).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ArgSpec {
+    /// Zero-based argument register index.
+    pub register: u8,
+    /// Offset into the memory the register points to; `None` uses the
+    /// register value itself.
+    pub offset: Option<u32>,
+    /// Follow the pointer stored at `offset` before reading the value.
+    pub deref: bool,
+    pub arg_type: ArgType,
+    /// Span attribute key.
+    pub name: String,
+}
+
+impl ArgSpec {
+    pub fn parse(spec: &str) -> io::Result<Self> {
+        let invalid = |reason: &str| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("invalid argument spec '{}': {}", spec, reason),
+            )
+        };
+
+        let caps = ARG_SPEC_REGEX
+            .captures(spec)
+            .ok_or_else(|| invalid("expected `argN[+OFFSET]: TYPE as \"name\"`"))?;
+
+        let register: u8 = caps["reg"].parse().map_err(|_| invalid("bad register"))?;
+        if register >= MAX_REGISTERS {
+            return Err(invalid("only arg0 to arg5 can be captured"));
+        }
+
+        let offset = caps
+            .name("offset")
+            .map(|offset| {
+                let offset = offset.as_str();
+                match offset.strip_prefix("0x") {
+                    Some(hex) => u32::from_str_radix(hex, 16),
+                    None => offset.parse(),
+                }
+                .map_err(|_| invalid("bad offset"))
+            })
+            .transpose()?;
+
+        let deref = caps.name("deref").is_some();
+        if deref != caps.name("close").is_some() {
+            return Err(invalid("unbalanced parentheses"));
+        }
+        if deref && offset.is_none() {
+            return Err(invalid(
+                "dereference requires a field offset, e.g. *(arg1+8)",
+            ));
+        }
+
+        let len = caps
+            .name("len")
+            .map(|len| len.as_str().parse::<usize>())
+            .transpose()
+            .map_err(|_| invalid("bad length"))?;
+        let buffer_len = || match len {
+            Some(len) if (1..=ARG_DATA_SIZE).contains(&len) => Ok(len as u8),
+            Some(_) => Err(invalid(&format!("length must be 1 to {}", ARG_DATA_SIZE))),
+            None => Err(invalid("str and bytes need a length, e.g. str(64)")),
+        };
+
+        let arg_type = match &caps["type"] {
+            "str" => ArgType::Str(buffer_len()?),
+            "bytes" => ArgType::Bytes(buffer_len()?),
+            other => match scalar_type(other) {
+                Some(_) if len.is_some() => return Err(invalid("scalar types take no length")),
+                Some(arg_type) => arg_type,
+                None => return Err(invalid(&format!("unknown type '{}'", other))),
+            },
+        };
+
+        Ok(Self {
+            register,
+            offset,
+            deref,
+            arg_type,
+            name: caps["name"].to_string(),
+        })
+    }
+
+    /// Parses the specs of one function, enforcing the `MAX_ARGS` limit.
+    pub fn parse_all(specs: &[String]) -> io::Result<Vec<Self>> {
+        if specs.len() > MAX_ARGS {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "at most {} arguments can be captured per function",
+                    MAX_ARGS
+                ),
+            ));
+        }
+        specs.iter().map(|spec| Self::parse(spec)).collect()
+    }
+
+    /// Encodes the spec as `struct arg_spec` in probe.bpf.c.
+    fn encode(&self, out: &mut Vec<u8>) {
+        let (kind, size) = match self.arg_type {
+            ArgType::Int { size, .. } => (ARG_KIND_INT, size),
+            ArgType::Bool => (ARG_KIND_INT, 1),
+            ArgType::Ptr => (ARG_KIND_INT, 8),
+            ArgType::Str(len) => (ARG_KIND_STR, len),
+            ArgType::Bytes(len) => (ARG_KIND_BYTES, len),
+        };
+        let mut flags = 0;
+        if self.offset.is_some() {
+            flags |= ARG_FLAG_FIELD;
+        }
+        if self.deref {
+            flags |= ARG_FLAG_DEREF;
+        }
+
+        out.extend_from_slice(&[self.register, kind, size, flags]);
+        out.extend_from_slice(&self.offset.unwrap_or(0).to_ne_bytes());
+    }
+
+    /// Turns the captured bytes for this argument into a span attribute.
+    pub fn decode(&self, data: &[u8]) -> KeyValue {
+        let raw = || {
+            let mut word = [0u8; 8];
+            word.copy_from_slice(&data[..8]);
+            u64::from_ne_bytes(word)
+        };
+
+        let value: Value = match self.arg_type {
+            ArgType::Int { .. } | ArgType::Bool | ArgType::Ptr => {
+                scalar_value(self.arg_type, raw())
+            }
+            ArgType::Str(len) => {
+                let data = &data[..len as usize];
+                let end = data.iter().position(|&b| b == 0).unwrap_or(data.len());
+                String::from_utf8_lossy(&data[..end]).into_owned().into()
+            }
+            ArgType::Bytes(len) => data[..len as usize]
+                .iter()
+                .map(|b| format!("{:02x}", b))
+                .collect::<String>()
+                .into(),
+        };
+
+        KeyValue::new(self.name.clone(), value)
+    }
+}
+
+/// When a return value marks the call as failed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorRule {
+    /// Negative, after sign-extending from the declared width (C `-errno`, `-1`).
+    Negative,
+    Nonzero,
+    /// Zero, e.g. a NULL pointer or `false`.
+    Zero,
+    /// A Rust `Result` returned in registers with the discriminant in the
+    /// low byte of the return register: 0 for `Ok`, 1 for `Err`.
+    ResultErr,
+}
+
+/// Return value settings of one function, as written in the config:
+/// `{"type": "i32", "error_when": "negative"}`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ReturnConfig {
+    /// Any integer type, `bool` or `ptr`.
+    #[serde(rename = "type")]
+    pub value_type: String,
+    #[serde(default)]
+    pub error_when: Option<ErrorRule>,
+}
+
+/// How to report the return register of a function.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ReturnSpec {
+    pub value_type: ArgType,
+    pub error_when: Option<ErrorRule>,
+}
+
+impl ReturnSpec {
+    /// Span attribute key of the return value.
+    pub const ATTRIBUTE: &'static str = "return_value";
+
+    pub fn from_config(config: &ReturnConfig) -> io::Result<Self> {
+        let value_type = scalar_type(&config.value_type).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "invalid return type '{}': expected an integer type, bool or ptr",
+                    config.value_type
+                ),
+            )
+        })?;
+        Ok(Self {
+            value_type,
+            error_when: config.error_when,
+        })
+    }
+
+    pub fn decode(&self, retval: u64) -> KeyValue {
+        KeyValue::new(Self::ATTRIBUTE, scalar_value(self.value_type, retval))
+    }
+
+    /// Encodes the error rule as `struct return_rule` in probe.bpf.c, so
+    /// calls counted in the kernel are told apart by outcome too.
+    fn encode(&self, out: &mut Vec<u8>) {
+        let error_when = match self.error_when {
+            None => ERROR_WHEN_NONE,
+            Some(ErrorRule::Negative) => ERROR_WHEN_NEGATIVE,
+            Some(ErrorRule::Nonzero) => ERROR_WHEN_NONZERO,
+            Some(ErrorRule::Zero) => ERROR_WHEN_ZERO,
+            Some(ErrorRule::ResultErr) => ERROR_WHEN_RESULT_ERR,
+        };
+        let size = (scalar_bits(self.value_type) / 8) as u8;
+        out.extend_from_slice(&[error_when, size, 0, 0]);
+    }
+
+    /// Whether `retval` matches the function's error rule.
+    pub fn is_error(&self, retval: u64) -> bool {
+        let value = truncate(self.value_type, retval);
+        match self.error_when {
+            None => false,
+            Some(ErrorRule::Negative) => sign_extend(self.value_type, value) < 0,
+            Some(ErrorRule::Nonzero) => value != 0,
+            Some(ErrorRule::Zero) => value == 0,
+            Some(ErrorRule::ResultErr) => retval & 0xff == 1,
+        }
+    }
+}
+
+/// Types that fit in a register: integers, `bool` and `ptr`.
+fn scalar_type(name: &str) -> Option<ArgType> {
+    let int = |size, signed| ArgType::Int { size, signed };
+    Some(match name {
+        "u8" => int(1, false),
+        "u16" => int(2, false),
+        "u32" => int(4, false),
+        "u64" => int(8, false),
+        "i8" => int(1, true),
+        "i16" => int(2, true),
+        "i32" => int(4, true),
+        "i64" => int(8, true),
+        "bool" => ArgType::Bool,
+        "ptr" => ArgType::Ptr,
+        _ => return None,
+    })
+}
+
+/// Width of a scalar type in bits.
+fn scalar_bits(arg_type: ArgType) -> u32 {
+    match arg_type {
+        ArgType::Int { size, .. } => size as u32 * 8,
+        ArgType::Bool => 8,
+        _ => 64,
+    }
+}
+
+/// Keeps only the low bits belonging to the type; the rest of the register
+/// is unspecified for narrow types.
+fn truncate(arg_type: ArgType, raw: u64) -> u64 {
+    let bits = scalar_bits(arg_type);
+    if bits == 64 {
+        raw
+    } else {
+        raw & ((1u64 << bits) - 1)
+    }
+}
+
+/// Sign-extends a truncated value from the type's width.
+fn sign_extend(arg_type: ArgType, value: u64) -> i64 {
+    let shift = 64 - scalar_bits(arg_type);
+    ((value << shift) as i64) >> shift
+}
+
+fn scalar_value(arg_type: ArgType, raw: u64) -> Value {
+    let value = truncate(arg_type, raw);
+    match arg_type {
+        ArgType::Int { signed: true, .. } => sign_extend(arg_type, value).into(),
+        ArgType::Bool => (value != 0).into(),
+        ArgType::Ptr => format!("{:#x}", value).into(),
+        _ => (value as i64).into(),
+    }
+}
+
+/// Encodes the per-function settings as `struct function_config` in probe.bpf.c.
+/// `flags` is a combination of the `FUNCTION_FLAG_*` constants.
+pub fn encode_function_config(
+    arguments: &[ArgSpec],
+    return_value: Option<&ReturnSpec>,
+    flags: u32,
+) -> Vec<u8> {
+    let mut out = Vec::with_capacity(FUNCTION_CONFIG_SIZE);
+    out.extend_from_slice(&(arguments.len() as u32).to_ne_bytes());
+    out.extend_from_slice(&flags.to_ne_bytes());
+    for arg in arguments {
+        arg.encode(&mut out);
+    }
+    out.resize(8 + MAX_ARGS * 8, 0);
+    match return_value {
+        Some(return_value) => return_value.encode(&mut out),
+        None => out.extend_from_slice(&[ERROR_WHEN_NONE, 0, 0, 0]),
+    }
+    out
+}
+
+/// Size of `struct function_config`.
+const FUNCTION_CONFIG_SIZE: usize = 8 + MAX_ARGS * 8 + 4;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn captured(value: &[u8]) -> [u8; ARG_DATA_SIZE] {
+        let mut data = [0u8; ARG_DATA_SIZE];
+        data[..value.len()].copy_from_slice(value);
+        data
+    }
+
+    #[test]
+    fn test_parse_register_integer() {
+        let spec = ArgSpec::parse(r#"arg0: u64 as "user_id""#).unwrap();
+        assert_eq!(
+            spec,
+            ArgSpec {
+                register: 0,
+                offset: None,
+                deref: false,
+                arg_type: ArgType::Int {
+                    size: 8,
+                    signed: false
+                },
+                name: "user_id".to_string(),
+            }
+        );
+    }
+
+    #[test]
+    fn test_parse_string_and_struct_fields() {
+        let path = ArgSpec::parse(r#"arg1: str(64) as "path""#).unwrap();
+        assert_eq!(path.arg_type, ArgType::Str(64));
+        assert_eq!(path.offset, None);
+
+        let field = ArgSpec::parse(r#"arg2 + 0x10: i32 as "req.len""#).unwrap();
+        assert_eq!(field.offset, Some(16));
+        assert!(!field.deref);
+
+        let pointer_field = ArgSpec::parse(r#"*(arg2+8): str(32) as "req.host""#).unwrap();
+        assert_eq!(pointer_field.offset, Some(8));
+        assert!(pointer_field.deref);
+    }
+
+    #[test]
+    fn test_parse_rejects_invalid_specs() {
+        for spec in [
+            r#"arg6: u64 as "x""#,
+            r#"arg0: str as "x""#,
+            r#"arg0: str(65) as "x""#,
+            r#"arg0: u32(4) as "x""#,
+            r#"arg0: f64 as "x""#,
+            r#"*(arg0): u64 as "x""#,
+            r#"*(arg0+8: u64 as "x""#,
+            r#"arg0: u64"#,
+        ] {
+            let err = ArgSpec::parse(spec).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidInput, "{}", spec);
+        }
+    }
+
+    #[test]
+    fn test_decode_values() {
+        let i32_spec = ArgSpec::parse(r#"arg0: i32 as "n""#).unwrap();
+        let decoded = i32_spec.decode(&captured(&(-5i64).to_ne_bytes()));
+        assert_eq!(decoded, KeyValue::new("n", -5i64));
+
+        let str_spec = ArgSpec::parse(r#"arg1: str(8) as "path""#).unwrap();
+        let decoded = str_spec.decode(&captured(b"/tmp\0junk"));
+        assert_eq!(decoded, KeyValue::new("path", "/tmp"));
+
+        let bytes_spec = ArgSpec::parse(r#"arg2: bytes(3) as "magic""#).unwrap();
+        let decoded = bytes_spec.decode(&captured(&[0xca, 0xfe, 0x01, 0xff]));
+        assert_eq!(decoded, KeyValue::new("magic", "cafe01"));
+    }
+
+    #[test]
+    fn test_return_value_error_rules() {
+        let spec = |value_type: &str, error_when| {
+            ReturnSpec::from_config(&ReturnConfig {
+                value_type: value_type.to_string(),
+                error_when: Some(error_when),
+            })
+            .unwrap()
+        };
+
+        // Upper register bits are garbage for an `int` return
+        let negative = spec("i32", ErrorRule::Negative);
+        assert!(negative.is_error(0xdead_beef_ffff_fffe));
+        assert!(!negative.is_error(0xffff_ffff_0000_0002));
+        assert_eq!(
+            negative.decode(0xdead_beef_ffff_fffe),
+            KeyValue::new(ReturnSpec::ATTRIBUTE, -2i64)
+        );
+
+        let nonzero = spec("u8", ErrorRule::Nonzero);
+        assert!(nonzero.is_error(1));
+        assert!(!nonzero.is_error(0x100));
+
+        assert!(spec("ptr", ErrorRule::Zero).is_error(0));
+
+        let result = spec("u64", ErrorRule::ResultErr);
+        assert!(result.is_error(0x7f00_0000_0000_0001));
+        assert!(!result.is_error(0));
+
+        let err = ReturnSpec::from_config(&ReturnConfig {
+            value_type: "str".to_string(),
+            error_when: None,
+        })
+        .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn test_encode_function_config_layout() {
+        let args = ArgSpec::parse_all(&[
+            r#"arg1: str(64) as "path""#.to_string(),
+            r#"*(arg2+8): u16 as "port""#.to_string(),
+        ])
+        .unwrap();
+        let return_value = ReturnSpec::from_config(&ReturnConfig {
+            value_type: "i32".to_string(),
+            error_when: Some(ErrorRule::Negative),
+        })
+        .unwrap();
+        let encoded = encode_function_config(&args, Some(&return_value), FUNCTION_FLAG_AGGREGATE);
+
+        assert_eq!(encoded.len(), FUNCTION_CONFIG_SIZE);
+        assert_eq!(&encoded[0..4], &2u32.to_ne_bytes());
+        assert_eq!(&encoded[4..8], &FUNCTION_FLAG_AGGREGATE.to_ne_bytes());
+        assert_eq!(&encoded[8..12], &[1, ARG_KIND_STR, 64, 0]);
+        assert_eq!(
+            &encoded[16..20],
+            &[2, ARG_KIND_INT, 2, ARG_FLAG_FIELD | ARG_FLAG_DEREF]
+        );
+        assert_eq!(&encoded[20..24], &8u32.to_ne_bytes());
+        assert_eq!(&encoded[40..44], &[ERROR_WHEN_NEGATIVE, 4, 0, 0]);
+
+        let encoded = encode_function_config(&[], None, FUNCTION_FLAG_COUNT);
+        assert_eq!(encoded.len(), FUNCTION_CONFIG_SIZE);
+        assert_eq!(&encoded[40..44], &[ERROR_WHEN_NONE, 0, 0, 0]);
+    }
+}
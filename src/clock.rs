@@ -0,0 +1,137 @@
+use nix::time::{clock_gettime, ClockId};
+use std::sync::atomic::{AtomicI64, AtomicU64, Ordering};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// How often the monotonic/realtime offset is re-sampled. NTP slews
+/// CLOCK_REALTIME relative to CLOCK_MONOTONIC, so a single startup sample
+/// drifts over the lifetime of the agent.
+const DEFAULT_RESYNC_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Number of paired reads taken per sync; the tightest pair wins.
+const SYNC_SAMPLES: usize = 5;
+
+/// Translates `bpf_ktime_get_ns()` timestamps (CLOCK_MONOTONIC, time since
+/// boot) into wall-clock `SystemTime`s.
+#[derive(Debug)]
+pub struct ClockTranslator {
+    // realtime_ns - monotonic_ns at the last sync
+    offset_ns: AtomicI64,
+    // monotonic time of the last sync
+    last_sync_ns: AtomicU64,
+    resync_interval: Duration,
+}
+
+impl ClockTranslator {
+    pub fn new() -> Self {
+        Self::with_resync_interval(DEFAULT_RESYNC_INTERVAL)
+    }
+
+    pub fn with_resync_interval(resync_interval: Duration) -> Self {
+        let translator = Self {
+            offset_ns: AtomicI64::new(0),
+            last_sync_ns: AtomicU64::new(0),
+            resync_interval,
+        };
+        translator.sync();
+        translator
+    }
+
+    /// Samples CLOCK_REALTIME around a CLOCK_MONOTONIC read and stores the
+    /// offset between the two, using the sample with the narrowest window.
+    pub fn sync(&self) {
+        let mut best: Option<(u64, i64, u64)> = None; // (window, offset, monotonic)
+
+        for _ in 0..SYNC_SAMPLES {
+            let before = realtime_ns();
+            let monotonic = monotonic_ns();
+            let after = realtime_ns();
+
+            let window = after.saturating_sub(before) as u64;
+            let offset = before + (after - before) / 2 - monotonic as i64;
+            if best.is_none_or(|(best_window, _, _)| window < best_window) {
+                best = Some((window, offset, monotonic));
+            }
+        }
+
+        if let Some((_, offset, monotonic)) = best {
+            self.offset_ns.store(offset, Ordering::Relaxed);
+            self.last_sync_ns.store(monotonic, Ordering::Relaxed);
+        }
+    }
+
+    /// Converts the start and end of a call, kernel monotonic timestamps in
+    /// nanoseconds, to wall-clock time. Both use the same offset, so a resync
+    /// cannot stretch or shrink the call.
+    pub fn to_system_times(&self, start_ns: u64, end_ns: u64) -> (SystemTime, SystemTime) {
+        self.maybe_resync();
+        let offset_ns = self.offset_ns.load(Ordering::Relaxed);
+        (
+            to_realtime(start_ns, offset_ns),
+            to_realtime(end_ns, offset_ns),
+        )
+    }
+
+    /// Re-samples the clocks if the last sync is older than the resync interval.
+    fn maybe_resync(&self) {
+        let elapsed = monotonic_ns().saturating_sub(self.last_sync_ns.load(Ordering::Relaxed));
+        if elapsed >= self.resync_interval.as_nanos() as u64 {
+            self.sync();
+        }
+    }
+}
+
+impl Default for ClockTranslator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn to_realtime(monotonic_ns: u64, offset_ns: i64) -> SystemTime {
+    let realtime_ns = monotonic_ns as i64 + offset_ns;
+    UNIX_EPOCH + Duration::from_nanos(realtime_ns.max(0) as u64)
+}
+
+fn realtime_ns() -> i64 {
+    let ts = clock_gettime(ClockId::CLOCK_REALTIME).expect("CLOCK_REALTIME is always available");
+    ts.tv_sec() * 1_000_000_000 + ts.tv_nsec()
+}
+
+fn monotonic_ns() -> u64 {
+    let ts = clock_gettime(ClockId::CLOCK_MONOTONIC).expect("CLOCK_MONOTONIC is always available");
+    (ts.tv_sec() * 1_000_000_000 + ts.tv_nsec()) as u64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_monotonic_now_maps_to_wall_clock_now() {
+        let clock = ClockTranslator::new();
+        let now_ns = monotonic_ns();
+        let (translated, _) = clock.to_system_times(now_ns, now_ns);
+        let now = SystemTime::now();
+
+        let skew = now
+            .duration_since(translated)
+            .unwrap_or_else(|e| e.duration());
+        assert!(
+            skew < Duration::from_millis(50),
+            "skew too large: {:?}",
+            skew
+        );
+    }
+
+    #[test]
+    fn test_translation_preserves_durations() {
+        // Resyncs on every call, which must not move the end relative to
+        // the start
+        let clock = ClockTranslator::with_resync_interval(Duration::ZERO);
+        let (start, end) = clock.to_system_times(1_000_000_000, 1_250_000_000);
+
+        assert_eq!(
+            end.duration_since(start).unwrap(),
+            Duration::from_millis(250)
+        );
+    }
+}
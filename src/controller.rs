@@ -1,65 +1,251 @@
-use opentelemetry::global;
-use opentelemetry::trace::{TraceResult, Tracer};
+use opentelemetry::trace::{
+    SpanContext, SpanId, Status, TraceContextExt, TraceError, TraceFlags, TraceId, TraceResult,
+    TraceState, Tracer,
+};
 
+use crate::clock::ClockTranslator;
+use crate::metrics::FunctionMetrics;
 use crate::probe::BPFEvent;
+use crate::registry::{FunctionRegistry, RegisteredFunction};
+use crate::resource::ProcessTracers;
+use crate::tail_sampling::TailSampler;
 use anyhow::Result;
-use opentelemetry::global::BoxedTracer;
 use opentelemetry::trace::Span;
-use opentelemetry::trace::TracerProvider;
-use opentelemetry::KeyValue;
+use opentelemetry::{Context, KeyValue};
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
 use tokio::sync::mpsc::Receiver;
+use tokio::sync::oneshot;
 use tracing::info;
 
+/// How often traces held by the tail sampler are checked for timeouts.
+const TAIL_SAMPLING_SWEEP_INTERVAL: Duration = Duration::from_secs(1);
+/// How often tracer providers of exited processes are shut down.
+const EXITED_PROCESS_SWEEP_INTERVAL: Duration = Duration::from_secs(30);
+
+/// A completed call, ready to be exported as a span.
+#[derive(Debug, Clone)]
+pub struct CallSpan {
+    pub name: String,
+    pub trace_id: u128,
+    pub span_id: u64,
+    /// 0 for the outermost traced call on a thread.
+    pub parent_span_id: u64,
+    pub start_time: SystemTime,
+    pub end_time: SystemTime,
+    pub attributes: Vec<KeyValue>,
+    pub status: Status,
+    pub pid: u32,
+    /// Instrumented binary the call was made in, if known.
+    pub binary_path: Option<String>,
+    pub service_name: Option<String>,
+}
+
+impl CallSpan {
+    pub fn duration(&self) -> Duration {
+        self.end_time
+            .duration_since(self.start_time)
+            .unwrap_or_default()
+    }
+}
+
 pub struct Controller {
-    tracer: BoxedTracer,
+    tracers: ProcessTracers,
     event_receiver: Receiver<BPFEvent>,
+    clock: ClockTranslator,
+    registry: FunctionRegistry,
+    metrics: Option<Arc<FunctionMetrics>>,
+    /// Holds spans until their trace is complete, when tail sampling is on.
+    tail_sampler: Option<TailSampler>,
 }
 
 impl Controller {
-    pub fn new(event_receiver: Receiver<BPFEvent>) -> Result<Self> {
-        let tracer = global::tracer_provider().tracer("ebpf_tracer");
-        println!("Got tracer: {:?}", tracer);
+    pub fn new(
+        event_receiver: Receiver<BPFEvent>,
+        registry: FunctionRegistry,
+        tracers: ProcessTracers,
+        metrics: Option<Arc<FunctionMetrics>>,
+        tail_sampler: Option<TailSampler>,
+    ) -> Result<Self> {
         Ok(Self {
-            tracer,
+            tracers,
             event_receiver,
+            clock: ClockTranslator::new(),
+            registry,
+            metrics,
+            tail_sampler,
         })
     }
 
-    pub async fn run(&mut self) -> Result<()> {
-        while let Some(event) = self.event_receiver.recv().await {
-            println!("received event---");
-            self.trace(event)?;
+    /// Handles events until `shutdown` fires or the event channel closes,
+    /// then handles the events already queued and flushes the tail sampler.
+    pub async fn run(&mut self, mut shutdown: oneshot::Receiver<()>) -> Result<()> {
+        let mut sweep = tokio::time::interval(TAIL_SAMPLING_SWEEP_INTERVAL);
+        let mut exited_sweep = tokio::time::interval(EXITED_PROCESS_SWEEP_INTERVAL);
+        loop {
+            tokio::select! {
+                event = self.event_receiver.recv() => {
+                    let Some(event) = event else { break };
+                    self.handle(event);
+                }
+                _ = &mut shutdown => {
+                    while let Ok(event) = self.event_receiver.try_recv() {
+                        self.handle(event);
+                    }
+                    break;
+                }
+                _ = sweep.tick() => {
+                    if let Some(sampler) = &mut self.tail_sampler {
+                        let spans = sampler.expire(Instant::now());
+                        self.export(spans);
+                    }
+                }
+                _ = exited_sweep.tick() => {
+                    let tracers = self.tracers.clone();
+                    tokio::task::spawn_blocking(move || tracers.remove_exited());
+                }
+            }
+        }
+
+        if let Some(sampler) = &mut self.tail_sampler {
+            let spans = sampler.flush();
+            self.export(spans);
         }
         Ok(())
     }
 
-    fn trace(&self, event: BPFEvent) -> TraceResult<()> {
-        println!("Tracing event: {:?}", event);
-        let span_name = format!("bpf_event: {}", String::from_utf8_lossy(&event.comm));
-        //let span_name = event.name.clone();
-        let mut span = self
-            .tracer
-            .span_builder(span_name)
-            .with_start_time(
-                std::time::UNIX_EPOCH + std::time::Duration::from_nanos(event.timestamp_start),
-            )
-            .with_attributes(vec![
-                KeyValue::new("pid", event.pid as i64),
-                KeyValue::new("timestamp_start", event.timestamp_start as i64),
-                KeyValue::new("timestamp_end", event.timestamp_end as i64),
-            ])
-            .start(&self.tracer);
+    fn handle(&mut self, event: BPFEvent) {
+        let function = self.registry.get(event.function_id);
+        // Sampled durations; calls themselves are counted in the kernel
+        if let (Some(metrics), Some(function)) = (&self.metrics, &function) {
+            metrics.record(function, event.duration(), function.is_error(event.retval));
+        }
+        let span = self.call_span(event, function.as_deref());
+        match &mut self.tail_sampler {
+            Some(sampler) => {
+                let spans = sampler.add(span, Instant::now());
+                self.export(spans)
+            }
+            None => self.export([span]),
+        }
+    }
+
+    /// Emits `spans`. A failure (e.g. building the exporter of a process)
+    /// only loses that span; tracing carries on with the next one.
+    fn export(&self, spans: impl IntoIterator<Item = CallSpan>) {
+        for span in spans {
+            let pid = span.pid;
+            if let Err(err) = self.emit(span) {
+                eprintln!("⚠️ Failed to export span of pid {}: {}", pid, err);
+            }
+        }
+    }
+
+    fn call_span(&self, event: BPFEvent, function: Option<&RegisteredFunction>) -> CallSpan {
+        let mut attributes = vec![
+            KeyValue::new("pid", event.pid as i64),
+            KeyValue::new("thread.id", event.tid as i64),
+            KeyValue::new("cpu.id", event.cpu as i64),
+            KeyValue::new("cgroup.id", event.cgroup_id as i64),
+            KeyValue::new("function_id", event.function_id as i64),
+            KeyValue::new("duration_ns", event.duration().as_nanos() as i64),
+        ];
+
+        let mut status = Status::Unset;
+        let span_name = match function {
+            Some(function) => {
+                attributes.push(KeyValue::new(
+                    "code.function",
+                    function.code_function().to_string(),
+                ));
+                if let Some(namespace) = function.code_namespace() {
+                    attributes.push(KeyValue::new("code.namespace", namespace.to_string()));
+                }
+                attributes.push(KeyValue::new("binary.path", function.binary_path.clone()));
+                for (i, spec) in function.info.arguments.iter().enumerate() {
+                    // Unreadable arguments (e.g. an unmapped pointer) are left out
+                    if event.arg_errors & (1 << i) == 0 {
+                        attributes.push(spec.decode(&event.args[i]));
+                    }
+                }
+                if let Some(return_value) = &function.info.return_value {
+                    attributes.push(return_value.decode(event.retval));
+                    if function.is_error(event.retval) {
+                        status = Status::error(format!(
+                            "return value matched error rule {:?}",
+                            return_value.error_when.unwrap()
+                        ));
+                    }
+                }
+                function.info.demangled_name.clone()
+            }
+            None => format!("unknown function {} ({})", event.function_id, event.comm()),
+        };
 
         info!(
-            "Captured event: pid={}, comm={}, start={}, end={}",
+            "Captured call: pid={}, tid={}, comm={}, start={}, end={}",
             event.pid,
-            String::from_utf8_lossy(&event.comm),
+            event.tid,
+            event.comm(),
             event.timestamp_start,
             event.timestamp_end
         );
 
-        info!("Captured event: {:?}", event);
-        span.end();
+        let (start_time, end_time) = self
+            .clock
+            .to_system_times(event.timestamp_start, event.timestamp_end);
+        CallSpan {
+            name: span_name,
+            trace_id: event.trace_id,
+            span_id: event.span_id,
+            parent_span_id: event.parent_span_id,
+            start_time,
+            end_time,
+            attributes,
+            status,
+            pid: event.pid,
+            binary_path: function.map(|function| function.binary_path.clone()),
+            service_name: function.and_then(|function| function.info.service_name.clone()),
+        }
+    }
+
+    fn emit(&self, call: CallSpan) -> TraceResult<()> {
+        // The ids were assigned in BPF on entry, when the enclosing call's
+        // span id was known; its span is only exported after this one ends.
+        let tracer = self
+            .tracers
+            .tracer(
+                call.pid,
+                call.binary_path.as_deref(),
+                call.service_name.as_deref(),
+            )
+            .map_err(|err| TraceError::Other(err.into()))?;
+        let parent = parent_context(&call);
+        let mut span = tracer
+            .span_builder(call.name)
+            .with_trace_id(TraceId::from(call.trace_id))
+            .with_span_id(SpanId::from(call.span_id))
+            .with_start_time(call.start_time)
+            .with_attributes(call.attributes)
+            .start_with_context(&tracer, &parent);
+
+        span.set_status(call.status);
+        span.end_with_timestamp(call.end_time);
         Ok(())
     }
 }
+
+/// Context carrying the enclosing call's span, or an empty context for the
+/// outermost traced call on a thread.
+fn parent_context(call: &CallSpan) -> Context {
+    if call.parent_span_id == 0 {
+        return Context::new();
+    }
+    Context::new().with_remote_span_context(SpanContext::new(
+        TraceId::from(call.trace_id),
+        SpanId::from(call.parent_span_id),
+        TraceFlags::SAMPLED,
+        false,
+        TraceState::default(),
+    ))
+}
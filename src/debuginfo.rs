@@ -0,0 +1,209 @@
+use goblin::elf::note::NT_GNU_BUILD_ID;
+use goblin::elf::Elf;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+const DEBUGLINK_SECTION: &str = ".gnu_debuglink";
+
+fn default_debug_dirs() -> Vec<String> {
+    vec!["/usr/lib/debug".to_string()]
+}
+
+fn default_debuginfod_timeout_secs() -> u64 {
+    30
+}
+
+/// Where to look for separate debug files of stripped binaries.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DebugInfoConfig {
+    /// Roots searched for `.build-id/xx/yyyy.debug` files and for
+    /// `.gnu_debuglink` targets mirrored under the binary's directory.
+    #[serde(default = "default_debug_dirs")]
+    pub debug_dirs: Vec<String>,
+    /// Last resort when no local debug file is found.
+    #[serde(default)]
+    pub debuginfod: DebuginfodConfig,
+}
+
+impl Default for DebugInfoConfig {
+    fn default() -> Self {
+        Self {
+            debug_dirs: default_debug_dirs(),
+            debuginfod: DebuginfodConfig::default(),
+        }
+    }
+}
+
+/// Settings for fetching debug info by build-id from debuginfod servers.
+/// Only honoured when the agent is built with the `debuginfod` feature.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DebuginfodConfig {
+    #[serde(default)]
+    pub enabled: bool,
+    /// Server base URLs, tried in order. Defaults to `$DEBUGINFOD_URLS`.
+    #[serde(default)]
+    pub urls: Vec<String>,
+    /// Download cache. Defaults to `$XDG_CACHE_HOME/ebpf-opentelemetry/debuginfod`.
+    #[serde(default)]
+    pub cache_dir: Option<String>,
+    #[serde(default = "default_debuginfod_timeout_secs")]
+    pub timeout_secs: u64,
+}
+
+impl Default for DebuginfodConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            urls: Vec::new(),
+            cache_dir: None,
+            timeout_secs: default_debuginfod_timeout_secs(),
+        }
+    }
+}
+
+#[cfg(feature = "debuginfod")]
+impl DebuginfodConfig {
+    pub fn server_urls(&self) -> Vec<String> {
+        if !self.urls.is_empty() {
+            return self.urls.clone();
+        }
+        std::env::var("DEBUGINFOD_URLS")
+            .map(|urls| urls.split_whitespace().map(str::to_string).collect())
+            .unwrap_or_default()
+    }
+
+    pub fn cache_dir(&self) -> PathBuf {
+        if let Some(cache_dir) = &self.cache_dir {
+            return PathBuf::from(cache_dir);
+        }
+        let cache_home = std::env::var_os("XDG_CACHE_HOME")
+            .map(PathBuf::from)
+            .or_else(|| std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".cache")))
+            .unwrap_or_else(std::env::temp_dir);
+        cache_home.join("ebpf-opentelemetry").join("debuginfod")
+    }
+}
+
+/// Returns the NT_GNU_BUILD_ID note of `elf`, if it has one.
+pub fn build_id(elf: &Elf, data: &[u8]) -> Option<Vec<u8>> {
+    let notes = elf
+        .iter_note_headers(data)
+        .into_iter()
+        .chain(elf.iter_note_sections(data, None))
+        .flatten();
+
+    notes
+        .filter_map(Result::ok)
+        .find(|note| note.n_type == NT_GNU_BUILD_ID && note.name.trim_end_matches('\0') == "GNU")
+        .map(|note| note.desc.to_vec())
+}
+
+/// Returns the file name and CRC32 recorded in `.gnu_debuglink`.
+pub fn debuglink(elf: &Elf, data: &[u8]) -> Option<(String, u32)> {
+    let section = elf
+        .section_headers
+        .iter()
+        .find(|sh| elf.shdr_strtab.get_at(sh.sh_name) == Some(DEBUGLINK_SECTION))?;
+    let start = section.sh_offset as usize;
+    let contents = data.get(start..start.checked_add(section.sh_size as usize)?)?;
+
+    // NUL-terminated file name, padded to a 4-byte boundary, then the CRC
+    let name_len = contents.iter().position(|&b| b == 0)?;
+    let crc_offset = (name_len + 1).next_multiple_of(4);
+    let crc_bytes: [u8; 4] = contents.get(crc_offset..crc_offset + 4)?.try_into().ok()?;
+    let crc = if elf.little_endian {
+        u32::from_le_bytes(crc_bytes)
+    } else {
+        u32::from_be_bytes(crc_bytes)
+    };
+
+    let name = String::from_utf8_lossy(&contents[..name_len]).into_owned();
+    Some((name, crc))
+}
+
+/// Path of the debug file for `build_id` under `debug_dir`, following the
+/// `.build-id/xx/yyyy.debug` layout used by distribution debug packages.
+pub fn build_id_path(debug_dir: &Path, build_id: &[u8]) -> Option<PathBuf> {
+    let (first, rest) = build_id.split_first()?;
+    let rest: String = rest.iter().map(|b| format!("{:02x}", b)).collect();
+    Some(
+        debug_dir
+            .join(".build-id")
+            .join(format!("{:02x}", first))
+            .join(format!("{}.debug", rest)),
+    )
+}
+
+/// Locates a separate debug file for the binary at `binary_path`: by
+/// build-id, then through `.gnu_debuglink` (whose CRC must match), and
+/// finally from debuginfod if enabled.
+pub fn find_debug_file(
+    binary_path: &Path,
+    elf: &Elf,
+    data: &[u8],
+    config: &DebugInfoConfig,
+) -> Option<PathBuf> {
+    let build_id = build_id(elf, data);
+    if let Some(build_id) = &build_id {
+        for debug_dir in &config.debug_dirs {
+            if let Some(path) = build_id_path(Path::new(debug_dir), build_id) {
+                if path.is_file() {
+                    return Some(path);
+                }
+            }
+        }
+    }
+
+    if let Some(path) = find_debuglink_target(binary_path, elf, data, config) {
+        return Some(path);
+    }
+
+    match build_id {
+        Some(build_id) if config.debuginfod.enabled => {
+            fetch_from_debuginfod(&config.debuginfod, &build_id)
+        }
+        _ => None,
+    }
+}
+
+#[cfg(feature = "debuginfod")]
+fn fetch_from_debuginfod(config: &DebuginfodConfig, build_id: &[u8]) -> Option<PathBuf> {
+    match crate::debuginfod::DebuginfodClient::new(config).fetch_debuginfo(build_id) {
+        Ok(path) => path,
+        Err(err) => {
+            eprintln!("⚠️ Failed to fetch debug info from debuginfod: {}", err);
+            None
+        }
+    }
+}
+
+#[cfg(not(feature = "debuginfod"))]
+fn fetch_from_debuginfod(_config: &DebuginfodConfig, _build_id: &[u8]) -> Option<PathBuf> {
+    eprintln!("⚠️ debuginfod is enabled in the config, but the agent was built without the `debuginfod` feature");
+    None
+}
+
+fn find_debuglink_target(
+    binary_path: &Path,
+    elf: &Elf,
+    data: &[u8],
+    config: &DebugInfoConfig,
+) -> Option<PathBuf> {
+    let (name, crc) = debuglink(elf, data)?;
+    let binary_dir = binary_path.parent().unwrap_or(Path::new("/"));
+    let mut candidates = vec![
+        binary_dir.join(&name),
+        binary_dir.join(".debug").join(&name),
+    ];
+    for debug_dir in &config.debug_dirs {
+        let relative_dir = binary_dir.strip_prefix("/").unwrap_or(binary_dir);
+        candidates.push(Path::new(debug_dir).join(relative_dir).join(&name));
+    }
+
+    candidates.into_iter().find(|candidate| {
+        // The link may name the binary itself when it lives in the same directory
+        candidate != binary_path
+            && fs::read(candidate).is_ok_and(|contents| crc32fast::hash(&contents) == crc)
+    })
+}
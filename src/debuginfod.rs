@@ -0,0 +1,220 @@
+use crate::debuginfo::{self, DebuginfodConfig};
+use goblin::elf::Elf;
+use std::fs;
+use std::io::{self, Read};
+use std::path::PathBuf;
+use std::time::Duration;
+
+/// Minimal client for the debuginfod protocol: `GET <url>/buildid/<id>/debuginfo`.
+/// Downloads are cached on disk under `<cache_dir>/<build-id>/debuginfo`.
+pub struct DebuginfodClient {
+    urls: Vec<String>,
+    cache_dir: PathBuf,
+    agent: ureq::Agent,
+}
+
+impl DebuginfodClient {
+    pub fn new(config: &DebuginfodConfig) -> Self {
+        Self {
+            urls: config.server_urls(),
+            cache_dir: config.cache_dir(),
+            agent: ureq::AgentBuilder::new()
+                .timeout(Duration::from_secs(config.timeout_secs))
+                .build(),
+        }
+    }
+
+    /// Returns the path of the cached debug file for `build_id`, downloading
+    /// it from the first server that has it. `Ok(None)` means no server knows
+    /// the build-id.
+    pub fn fetch_debuginfo(&self, build_id: &[u8]) -> io::Result<Option<PathBuf>> {
+        let expected_build_id = build_id;
+        let build_id: String = build_id.iter().map(|b| format!("{:02x}", b)).collect();
+        let cached = self.cache_dir.join(&build_id).join("debuginfo");
+        if cached.is_file() {
+            return Ok(Some(cached));
+        }
+
+        for url in &self.urls {
+            let request_url = format!(
+                "{}/buildid/{}/debuginfo",
+                url.trim_end_matches('/'),
+                build_id
+            );
+            println!("Querying debuginfod: {}", request_url);
+
+            let response = match self.agent.get(&request_url).call() {
+                Ok(response) => response,
+                Err(ureq::Error::Status(404, _)) => continue,
+                Err(err) => {
+                    eprintln!("⚠️ debuginfod request to {} failed: {}", request_url, err);
+                    continue;
+                }
+            };
+
+            let mut contents = Vec::new();
+            response.into_reader().read_to_end(&mut contents)?;
+            // A truncated download or a wrong file would otherwise be cached
+            // for good and resolve offsets from another binary
+            if !has_build_id(&contents, expected_build_id) {
+                eprintln!(
+                    "⚠️ debuginfod response from {} does not have build-id {}, ignoring it",
+                    request_url, build_id
+                );
+                continue;
+            }
+
+            // Write then rename so a partial download is never mistaken for a cache hit
+            fs::create_dir_all(cached.parent().unwrap())?;
+            let partial = cached.with_extension("partial");
+            fs::write(&partial, &contents)?;
+            fs::rename(&partial, &cached)?;
+            return Ok(Some(cached));
+        }
+
+        Ok(None)
+    }
+}
+
+/// Whether `contents` is an ELF file whose build-id is `build_id`.
+fn has_build_id(contents: &[u8], build_id: &[u8]) -> bool {
+    Elf::parse(contents)
+        .ok()
+        .and_then(|elf| debuginfo::build_id(&elf, contents))
+        .is_some_and(|found| found == build_id)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{BufRead, BufReader, Write};
+    use std::net::TcpListener;
+    use std::process::Command;
+    use std::thread;
+
+    /// Links a small executable whose build-id is `build_id` (hex) and
+    /// returns its contents.
+    fn elf_with_build_id(name: &str, build_id: &str) -> Vec<u8> {
+        let dir = std::env::temp_dir();
+        let source = dir.join(format!("{}.c", name));
+        let binary = dir.join(name);
+        fs::write(&source, "int main() { return 0; }").unwrap();
+        let output = Command::new("gcc")
+            .arg(format!("-Wl,--build-id=0x{}", build_id))
+            .arg("-o")
+            .arg(&binary)
+            .arg(&source)
+            .output()
+            .unwrap();
+        assert!(
+            output.status.success(),
+            "Compilation failed: {}",
+            String::from_utf8_lossy(&output.stderr)
+        );
+        let contents = fs::read(&binary).unwrap();
+        let _ = fs::remove_file(&source);
+        let _ = fs::remove_file(&binary);
+        contents
+    }
+
+    /// Serves `requests` HTTP requests, answering `/buildid/<id>/debuginfo`
+    /// for `build_id` with `body` and everything else with a 404.
+    fn serve_debuginfod(build_id: &'static str, body: Vec<u8>, requests: usize) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+
+        thread::spawn(move || {
+            for stream in listener.incoming().take(requests) {
+                let mut stream = stream.unwrap();
+                let mut request_line = String::new();
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+                reader.read_line(&mut request_line).unwrap();
+                // Drain the headers
+                let mut line = String::new();
+                while reader.read_line(&mut line).unwrap() > 2 {
+                    line.clear();
+                }
+
+                let expected = format!("GET /buildid/{}/debuginfo ", build_id);
+                let response: Vec<u8> = if request_line.starts_with(&expected) {
+                    let mut response =
+                        format!("HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n", body.len())
+                            .into_bytes();
+                    response.extend_from_slice(&body);
+                    response
+                } else {
+                    b"HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\n\r\n".to_vec()
+                };
+                stream.write_all(&response).unwrap();
+            }
+        });
+
+        url
+    }
+
+    fn client(urls: Vec<String>, cache_name: &str) -> DebuginfodClient {
+        let cache_dir = std::env::temp_dir().join(cache_name);
+        let _ = fs::remove_dir_all(&cache_dir);
+        DebuginfodClient::new(&DebuginfodConfig {
+            enabled: true,
+            urls,
+            cache_dir: Some(cache_dir.to_str().unwrap().to_string()),
+            ..Default::default()
+        })
+    }
+
+    #[test]
+    fn test_fetch_downloads_and_caches_debuginfo() {
+        let debug_file = elf_with_build_id("debuginfod_test_cached", "abcdef01");
+        let url = serve_debuginfod("abcdef01", debug_file.clone(), 1);
+        let client = client(vec![url], "debuginfod_test_cache");
+
+        let path = client
+            .fetch_debuginfo(&[0xab, 0xcd, 0xef, 0x01])
+            .unwrap()
+            .unwrap();
+        assert_eq!(fs::read(&path).unwrap(), debug_file);
+
+        // The stand-in only answers once, so this must be served from the cache
+        let cached = client
+            .fetch_debuginfo(&[0xab, 0xcd, 0xef, 0x01])
+            .unwrap()
+            .unwrap();
+        assert_eq!(cached, path);
+
+        let _ = fs::remove_dir_all(client.cache_dir);
+    }
+
+    #[test]
+    fn test_unknown_build_id_falls_through_servers() {
+        let first = serve_debuginfod("00000000", b"wrong".to_vec(), 2);
+        let debug_file = elf_with_build_id("debuginfod_test_fallthrough", "12345678");
+        let second = serve_debuginfod("12345678", debug_file.clone(), 2);
+        let client = client(vec![first, second], "debuginfod_test_fallthrough");
+
+        let path = client
+            .fetch_debuginfo(&[0x12, 0x34, 0x56, 0x78])
+            .unwrap()
+            .unwrap();
+        assert_eq!(fs::read(&path).unwrap(), debug_file);
+        // Both servers answer 404
+        assert!(client.fetch_debuginfo(&[0xff]).unwrap().is_none());
+
+        let _ = fs::remove_dir_all(client.cache_dir);
+    }
+
+    #[test]
+    fn test_mismatched_build_id_is_not_cached() {
+        let other = elf_with_build_id("debuginfod_test_mismatch", "87654321");
+        let url = serve_debuginfod("12345678", other[..other.len() / 2].to_vec(), 1);
+        let client = client(vec![url], "debuginfod_test_mismatch");
+
+        assert!(client
+            .fetch_debuginfo(&[0x12, 0x34, 0x56, 0x78])
+            .unwrap()
+            .is_none());
+        assert!(!client.cache_dir.join("12345678").join("debuginfo").exists());
+
+        let _ = fs::remove_dir_all(client.cache_dir);
+    }
+}
@@ -0,0 +1,545 @@
+use anyhow::Result;
+use async_trait::async_trait;
+use bytes::Bytes;
+use flate2::write::GzEncoder;
+use opentelemetry_http::{HttpClient, HttpError, Request, Response};
+use opentelemetry_otlp::{WithExportConfig, WithHttpConfig, WithTonicConfig};
+use opentelemetry_sdk::metrics::{PeriodicReader, SdkMeterProvider, View};
+use opentelemetry_sdk::runtime;
+use opentelemetry_sdk::trace::{BatchConfigBuilder, BatchSpanProcessor, TracerProvider};
+use opentelemetry_sdk::Resource;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::io::{self, Write};
+use std::time::Duration;
+
+const DEFAULT_GRPC_ENDPOINT: &str = "http://localhost:4317";
+const DEFAULT_HTTP_ENDPOINT: &str = "http://localhost:4318";
+const HTTP_TRACES_PATH: &str = "/v1/traces";
+const HTTP_METRICS_PATH: &str = "/v1/metrics";
+
+fn default_timeout_secs() -> u64 {
+    10
+}
+
+fn default_max_queue_size() -> usize {
+    2048
+}
+
+fn default_max_export_batch_size() -> usize {
+    512
+}
+
+fn default_scheduled_delay_ms() -> u64 {
+    5000
+}
+
+fn default_max_processes() -> usize {
+    64
+}
+
+/// Where finished spans are sent.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ExporterProtocol {
+    /// Print spans to stdout as they end. Meant for debugging.
+    #[default]
+    Stdout,
+    /// OTLP over gRPC.
+    Grpc,
+    /// OTLP over HTTP with protobuf payloads.
+    HttpProtobuf,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Compression {
+    Gzip,
+}
+
+/// Batch span processor tuning, used by the OTLP exporters.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BatchConfig {
+    /// Spans buffered before new ones are dropped.
+    #[serde(default = "default_max_queue_size")]
+    pub max_queue_size: usize,
+    #[serde(default = "default_max_export_batch_size")]
+    pub max_export_batch_size: usize,
+    /// Delay between two exports when the batch does not fill up.
+    #[serde(default = "default_scheduled_delay_ms")]
+    pub scheduled_delay_ms: u64,
+}
+
+impl Default for BatchConfig {
+    fn default() -> Self {
+        Self {
+            max_queue_size: default_max_queue_size(),
+            max_export_batch_size: default_max_export_batch_size(),
+            scheduled_delay_ms: default_scheduled_delay_ms(),
+        }
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct ExporterConfig {
+    #[serde(default)]
+    pub protocol: ExporterProtocol,
+    /// Collector endpoint. Defaults to `http://localhost:4317` for gRPC and
+    /// `http://localhost:4318/v1/traces` (or `/v1/metrics`) for HTTP.
+    #[serde(default)]
+    pub endpoint: Option<String>,
+    /// Sent with every export request, e.g. for authentication.
+    #[serde(default)]
+    pub headers: HashMap<String, String>,
+    #[serde(default)]
+    pub compression: Option<Compression>,
+    #[serde(default = "default_timeout_secs")]
+    pub timeout_secs: u64,
+    /// Only used for spans.
+    #[serde(default)]
+    pub batch: BatchConfig,
+    /// Processes with their own span exporter at a time. Only used for spans.
+    #[serde(default = "default_max_processes")]
+    pub max_processes: usize,
+}
+
+impl Default for ExporterConfig {
+    fn default() -> Self {
+        Self {
+            protocol: ExporterProtocol::default(),
+            endpoint: None,
+            headers: HashMap::new(),
+            compression: None,
+            timeout_secs: default_timeout_secs(),
+            batch: BatchConfig::default(),
+            max_processes: default_max_processes(),
+        }
+    }
+}
+
+// Header values usually carry credentials and the config is logged, so
+// only the header names are shown
+impl fmt::Debug for ExporterConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let headers: BTreeMap<&str, &str> = self
+            .headers
+            .keys()
+            .map(|key| (key.as_str(), "<redacted>"))
+            .collect();
+        f.debug_struct("ExporterConfig")
+            .field("protocol", &self.protocol)
+            .field("endpoint", &self.endpoint)
+            .field("headers", &headers)
+            .field("compression", &self.compression)
+            .field("timeout_secs", &self.timeout_secs)
+            .field("batch", &self.batch)
+            .field("max_processes", &self.max_processes)
+            .finish()
+    }
+}
+
+impl ExporterConfig {
+    /// Rejects settings that would only fail once spans are exported.
+    pub fn validate(&self) -> io::Result<()> {
+        let invalid = |reason: String| Err(io::Error::new(io::ErrorKind::InvalidInput, reason));
+
+        if self.batch.max_queue_size == 0 || self.batch.max_export_batch_size == 0 {
+            return invalid("exporter batch and queue sizes must be positive".to_string());
+        }
+        if self.max_processes == 0 {
+            return invalid("exporter max_processes must be positive".to_string());
+        }
+        if self.batch.max_export_batch_size > self.batch.max_queue_size {
+            return invalid(format!(
+                "exporter max_export_batch_size ({}) exceeds max_queue_size ({})",
+                self.batch.max_export_batch_size, self.batch.max_queue_size
+            ));
+        }
+        for (key, value) in &self.headers {
+            if key.parse::<http::HeaderName>().is_err()
+                || value.parse::<http::HeaderValue>().is_err()
+            {
+                return invalid(format!("invalid exporter header '{}'", key));
+            }
+        }
+        Ok(())
+    }
+
+    /// The configured endpoint, or the default one for the protocol. HTTP
+    /// defaults include the signal's path, e.g. `/v1/traces`.
+    fn endpoint(&self, http_path: &str) -> String {
+        match (&self.endpoint, self.protocol) {
+            (Some(endpoint), _) => endpoint.clone(),
+            (None, ExporterProtocol::HttpProtobuf) => {
+                format!("{}{}", DEFAULT_HTTP_ENDPOINT, http_path)
+            }
+            (None, _) => DEFAULT_GRPC_ENDPOINT.to_string(),
+        }
+    }
+
+    fn timeout(&self) -> Duration {
+        Duration::from_secs(self.timeout_secs)
+    }
+
+    /// Applies the endpoint, timeout, headers and compression to a span or
+    /// metric exporter builder for gRPC.
+    fn configure_tonic<B: WithExportConfig + WithTonicConfig>(&self, builder: B) -> Result<B> {
+        let headers = self
+            .headers
+            .iter()
+            .map(|(key, value)| Ok((key.parse()?, value.parse()?)))
+            .collect::<Result<http::HeaderMap>>()?;
+        let builder = builder
+            .with_endpoint(self.endpoint(""))
+            .with_timeout(self.timeout())
+            .with_metadata(tonic::metadata::MetadataMap::from_headers(headers));
+        Ok(match self.compression {
+            Some(Compression::Gzip) => {
+                builder.with_compression(opentelemetry_otlp::Compression::Gzip)
+            }
+            None => builder,
+        })
+    }
+
+    /// Same as `configure_tonic`, for HTTP/protobuf.
+    fn configure_http<B: WithExportConfig + WithHttpConfig>(
+        &self,
+        builder: B,
+        http_path: &str,
+    ) -> Result<B> {
+        let client = reqwest::Client::builder().timeout(self.timeout()).build()?;
+        let builder = builder
+            .with_endpoint(self.endpoint(http_path))
+            .with_timeout(self.timeout())
+            .with_headers(self.headers.clone());
+        Ok(match self.compression {
+            Some(Compression::Gzip) => builder.with_http_client(GzipHttpClient(client)),
+            None => builder.with_http_client(client),
+        })
+    }
+}
+
+/// Builds a tracer provider for the configured exporter whose spans carry
+/// `resource`. The OTLP exporters must be built inside the Tokio runtime.
+pub fn build_tracer_provider(
+    config: &ExporterConfig,
+    resource: Resource,
+) -> Result<TracerProvider> {
+    config.validate()?;
+
+    let exporter = match config.protocol {
+        ExporterProtocol::Stdout => {
+            return Ok(TracerProvider::builder()
+                .with_simple_exporter(opentelemetry_stdout::SpanExporter::default())
+                .with_resource(resource)
+                .build());
+        }
+        ExporterProtocol::Grpc => config
+            .configure_tonic(opentelemetry_otlp::SpanExporter::builder().with_tonic())?
+            .build()?,
+        ExporterProtocol::HttpProtobuf => config
+            .configure_http(
+                opentelemetry_otlp::SpanExporter::builder().with_http(),
+                HTTP_TRACES_PATH,
+            )?
+            .build()?,
+    };
+
+    let batch_config = BatchConfigBuilder::default()
+        .with_max_queue_size(config.batch.max_queue_size)
+        .with_max_export_batch_size(config.batch.max_export_batch_size)
+        .with_scheduled_delay(Duration::from_millis(config.batch.scheduled_delay_ms))
+        .with_max_export_timeout(config.timeout())
+        .build();
+    let processor = BatchSpanProcessor::builder(exporter, runtime::Tokio)
+        .with_batch_config(batch_config)
+        .build();
+
+    Ok(TracerProvider::builder()
+        .with_span_processor(processor)
+        .with_resource(resource)
+        .build())
+}
+
+/// Builds a meter provider that pushes to the configured exporter every
+/// `interval`, with metrics carrying `resource`. Must be called inside the
+/// Tokio runtime.
+pub fn build_meter_provider(
+    config: &ExporterConfig,
+    interval: Duration,
+    views: Vec<Box<dyn View>>,
+    resource: Resource,
+) -> Result<SdkMeterProvider> {
+    config.validate()?;
+
+    let reader = match config.protocol {
+        ExporterProtocol::Stdout => PeriodicReader::builder(
+            opentelemetry_stdout::MetricExporter::default(),
+            runtime::Tokio,
+        )
+        .with_interval(interval)
+        .build(),
+        ExporterProtocol::Grpc => {
+            let exporter = config
+                .configure_tonic(opentelemetry_otlp::MetricExporter::builder().with_tonic())?
+                .build()?;
+            PeriodicReader::builder(exporter, runtime::Tokio)
+                .with_interval(interval)
+                .with_timeout(config.timeout())
+                .build()
+        }
+        ExporterProtocol::HttpProtobuf => {
+            let exporter = config
+                .configure_http(
+                    opentelemetry_otlp::MetricExporter::builder().with_http(),
+                    HTTP_METRICS_PATH,
+                )?
+                .build()?;
+            PeriodicReader::builder(exporter, runtime::Tokio)
+                .with_interval(interval)
+                .with_timeout(config.timeout())
+                .build()
+        }
+    };
+
+    let mut builder = SdkMeterProvider::builder()
+        .with_reader(reader)
+        .with_resource(resource);
+    for view in views {
+        builder = builder.with_view(view);
+    }
+    Ok(builder.build())
+}
+
+/// Gzips request bodies. The OTLP HTTP exporter only supports compression
+/// for gRPC, so it is applied at the client instead.
+#[derive(Debug)]
+struct GzipHttpClient(reqwest::Client);
+
+#[async_trait]
+impl HttpClient for GzipHttpClient {
+    async fn send(&self, request: Request<Vec<u8>>) -> Result<Response<Bytes>, HttpError> {
+        let (mut parts, body) = request.into_parts();
+        let mut encoder = GzEncoder::new(Vec::new(), flate2::Compression::default());
+        encoder.write_all(&body)?;
+        let body = encoder.finish()?;
+
+        parts.headers.insert(
+            http::header::CONTENT_ENCODING,
+            http::HeaderValue::from_static("gzip"),
+        );
+        self.0.send(Request::from_parts(parts, body)).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::read::GzDecoder;
+    use opentelemetry::trace::{Span, Tracer, TracerProvider as _};
+    use opentelemetry_proto::tonic::collector::trace::v1::trace_service_server::{
+        TraceService, TraceServiceServer,
+    };
+    use opentelemetry_proto::tonic::collector::trace::v1::{
+        ExportTraceServiceRequest, ExportTraceServiceResponse,
+    };
+    use std::io::{BufRead, BufReader, Read};
+    use std::net::TcpListener;
+    use std::sync::mpsc;
+    use std::thread;
+    use tonic::codec::CompressionEncoding;
+    use tonic::metadata::MetadataMap;
+    use tonic::transport::server::TcpIncoming;
+    use tonic::transport::Server;
+
+    /// A request received by the mock collector.
+    struct ReceivedRequest {
+        request_line: String,
+        headers: HashMap<String, String>,
+        body: Vec<u8>,
+    }
+
+    /// Accepts one OTLP/HTTP export request, answers it with an empty
+    /// success response and hands it to the test.
+    fn mock_collector() -> (String, mpsc::Receiver<ReceivedRequest>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let endpoint = format!("http://{}/v1/traces", listener.local_addr().unwrap());
+        let (sender, receiver) = mpsc::channel();
+
+        thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            let mut request_line = String::new();
+            reader.read_line(&mut request_line).unwrap();
+
+            let mut headers = HashMap::new();
+            loop {
+                let mut line = String::new();
+                reader.read_line(&mut line).unwrap();
+                let Some((key, value)) = line.trim_end().split_once(": ") else {
+                    break;
+                };
+                headers.insert(key.to_lowercase(), value.to_string());
+            }
+
+            let length: usize = headers["content-length"].parse().unwrap();
+            let mut body = vec![0u8; length];
+            reader.read_exact(&mut body).unwrap();
+
+            stream
+                .write_all(b"HTTP/1.1 200 OK\r\nContent-Type: application/x-protobuf\r\nContent-Length: 0\r\n\r\n")
+                .unwrap();
+            sender
+                .send(ReceivedRequest {
+                    request_line,
+                    headers,
+                    body,
+                })
+                .unwrap();
+        });
+
+        (endpoint, receiver)
+    }
+
+    /// An export call received by the mock gRPC collector.
+    struct ReceivedExport {
+        metadata: MetadataMap,
+        request: ExportTraceServiceRequest,
+    }
+
+    struct MockTraceService(mpsc::Sender<ReceivedExport>);
+
+    #[tonic::async_trait]
+    impl TraceService for MockTraceService {
+        async fn export(
+            &self,
+            request: tonic::Request<ExportTraceServiceRequest>,
+        ) -> Result<tonic::Response<ExportTraceServiceResponse>, tonic::Status> {
+            let (metadata, _, request) = request.into_parts();
+            let _ = self.0.send(ReceivedExport { metadata, request });
+            Ok(tonic::Response::new(ExportTraceServiceResponse::default()))
+        }
+    }
+
+    /// Serves the OTLP trace service, accepting gzip requests, and hands
+    /// each export call to the test.
+    async fn mock_grpc_collector() -> (String, mpsc::Receiver<ReceivedExport>) {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let endpoint = format!("http://{}", listener.local_addr().unwrap());
+        let incoming = TcpIncoming::from_listener(listener, true, None).unwrap();
+        let (sender, receiver) = mpsc::channel();
+
+        let service = TraceServiceServer::new(MockTraceService(sender))
+            .accept_compressed(CompressionEncoding::Gzip);
+        tokio::spawn(
+            Server::builder()
+                .add_service(service)
+                .serve_with_incoming(incoming),
+        );
+
+        (endpoint, receiver)
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_http_exporter_sends_to_collector() {
+        let (endpoint, received) = mock_collector();
+        let config = ExporterConfig {
+            protocol: ExporterProtocol::HttpProtobuf,
+            endpoint: Some(endpoint),
+            headers: HashMap::from([("x-api-key".to_string(), "secret".to_string())]),
+            compression: Some(Compression::Gzip),
+            ..Default::default()
+        };
+
+        let provider = build_tracer_provider(&config, Resource::default()).unwrap();
+        let mut span = provider.tracer("test").start("exported_function");
+        span.end();
+        for result in provider.force_flush() {
+            result.unwrap();
+        }
+
+        let request = received
+            .recv_timeout(Duration::from_secs(10))
+            .expect("collector received no request");
+        assert!(request.request_line.starts_with("POST /v1/traces "));
+        assert_eq!(request.headers["content-type"], "application/x-protobuf");
+        assert_eq!(request.headers["content-encoding"], "gzip");
+        assert_eq!(request.headers["x-api-key"], "secret");
+
+        let mut payload = Vec::new();
+        GzDecoder::new(&request.body[..])
+            .read_to_end(&mut payload)
+            .unwrap();
+        // The span name is carried verbatim in the protobuf payload
+        assert!(payload
+            .windows(b"exported_function".len())
+            .any(|window| window == b"exported_function"));
+
+        let _ = provider.shutdown();
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_grpc_exporter_sends_to_collector() {
+        let (endpoint, received) = mock_grpc_collector().await;
+        let config = ExporterConfig {
+            protocol: ExporterProtocol::Grpc,
+            endpoint: Some(endpoint),
+            headers: HashMap::from([("authorization".to_string(), "Bearer token".to_string())]),
+            compression: Some(Compression::Gzip),
+            ..Default::default()
+        };
+
+        let provider = build_tracer_provider(&config, Resource::default()).unwrap();
+        let mut span = provider.tracer("test").start("exported_function");
+        span.end();
+        for result in provider.force_flush() {
+            result.unwrap();
+        }
+
+        let export = received
+            .recv_timeout(Duration::from_secs(10))
+            .expect("collector received no export");
+        let header = |key: &str| {
+            export
+                .metadata
+                .get(key)
+                .map(|value| value.to_str().unwrap())
+        };
+        assert_eq!(header("authorization"), Some("Bearer token"));
+        assert_eq!(header("grpc-encoding"), Some("gzip"));
+
+        let names: Vec<&str> = export
+            .request
+            .resource_spans
+            .iter()
+            .flat_map(|resource_spans| &resource_spans.scope_spans)
+            .flat_map(|scope_spans| &scope_spans.spans)
+            .map(|span| span.name.as_str())
+            .collect();
+        assert_eq!(names, vec!["exported_function"]);
+
+        let _ = provider.shutdown();
+    }
+
+    #[test]
+    fn test_header_values_are_redacted_in_debug_output() {
+        let config = ExporterConfig {
+            headers: HashMap::from([("authorization".to_string(), "Bearer token".to_string())]),
+            ..Default::default()
+        };
+        let debug = format!("{:?}", config);
+        assert!(debug.contains("authorization"), "{}", debug);
+        assert!(!debug.contains("Bearer token"), "{}", debug);
+    }
+
+    #[test]
+    fn test_invalid_batch_config_is_rejected() {
+        let config: ExporterConfig = serde_json::from_str(
+            r#"{"protocol": "http_protobuf", "batch": {"max_queue_size": 10, "max_export_batch_size": 100}}"#,
+        )
+        .unwrap();
+        let err = config.validate().unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    }
+}
@@ -0,0 +1,267 @@
+use crate::resource;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::sync::LazyLock;
+
+/// Mount point of the cgroup v2 hierarchy.
+const CGROUP_ROOT: &str = "/sys/fs/cgroup";
+
+// Full or abbreviated (as printed by `docker ps`) container id
+static CONTAINER_ID_REGEX: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"^[0-9a-f]{12,64}$").unwrap());
+
+/// Restricts tracing to some of the processes running the instrumented
+/// binaries. A process is traced when it matches any of the given criteria;
+/// with none given, every process is traced.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct FilterConfig {
+    #[serde(default)]
+    pub pids: Vec<u32>,
+    /// cgroup v2 directory, absolute or relative to `/sys/fs/cgroup`, e.g. a
+    /// pod's `kubepods.slice/.../kubepods-pod<uid>.slice`. Tasks in nested
+    /// cgroups are traced too.
+    #[serde(default)]
+    pub cgroup_path: Option<String>,
+    /// Id of a running container, full or abbreviated.
+    #[serde(default)]
+    pub container_id: Option<String>,
+}
+
+/// How a filter is enforced: by allowlists checked in the BPF program and,
+/// for a single process, also by attaching probes to it alone.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ResolvedFilter {
+    pub attach_pid: Option<u32>,
+    pub tgids: Vec<u32>,
+    pub cgroup_ids: Vec<u64>,
+}
+
+impl ResolvedFilter {
+    /// Whether probes can be attached to `pid`, given that they are attached
+    /// to the filtered process alone when there is exactly one.
+    pub fn attaches_to(&self, pid: u32) -> bool {
+        self.attach_pid.is_none_or(|attach_pid| attach_pid == pid)
+    }
+}
+
+impl FilterConfig {
+    pub fn is_empty(&self) -> bool {
+        self.pids.is_empty() && self.cgroup_path.is_none() && self.container_id.is_none()
+    }
+
+    pub fn validate(&self) -> io::Result<()> {
+        if self.pids.contains(&0) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "filter pids must be positive",
+            ));
+        }
+        if let Some(container_id) = &self.container_id {
+            if !CONTAINER_ID_REGEX.is_match(container_id) {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("Invalid container id '{}'", container_id),
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// Looks up the cgroup ids the filter refers to.
+    pub fn resolve(&self) -> io::Result<ResolvedFilter> {
+        self.resolve_in(Path::new("/proc"), Path::new(CGROUP_ROOT))
+    }
+
+    fn resolve_in(&self, proc_root: &Path, cgroup_root: &Path) -> io::Result<ResolvedFilter> {
+        // A uprobe attached to one pid is only hit by that process, which
+        // avoids running the BPF program in every other one. The allowlist
+        // still covers probes attached to another pid on request.
+        if let ([pid], None, None) = (self.pids.as_slice(), &self.cgroup_path, &self.container_id) {
+            return Ok(ResolvedFilter {
+                attach_pid: Some(*pid),
+                tgids: vec![*pid],
+                ..Default::default()
+            });
+        }
+
+        let mut cgroup_ids = Vec::new();
+        if let Some(path) = &self.cgroup_path {
+            let path = Path::new(path);
+            let path = if path.starts_with(cgroup_root) {
+                path.to_path_buf()
+            } else {
+                cgroup_root.join(path.strip_prefix("/").unwrap_or(path))
+            };
+            cgroup_ids.push(cgroup_id(&path)?);
+        }
+        if let Some(container_id) = &self.container_id {
+            let path = container_cgroup(container_id, proc_root, cgroup_root)?;
+            println!("Container {} is in cgroup {}", container_id, path.display());
+            cgroup_ids.push(cgroup_id(&path)?);
+        }
+
+        Ok(ResolvedFilter {
+            attach_pid: None,
+            tgids: self.pids.clone(),
+            cgroup_ids,
+        })
+    }
+}
+
+/// The id of a cgroup v2 directory is its inode number, which is what
+/// `bpf_get_current_cgroup_id()` reports.
+fn cgroup_id(path: &Path) -> io::Result<u64> {
+    fs::metadata(path)
+        .map(|metadata| metadata.ino())
+        .map_err(|err| io::Error::new(err.kind(), format!("cgroup {}: {}", path.display(), err)))
+}
+
+/// Finds the cgroup v2 directory of a running container from the
+/// `/proc/<pid>/cgroup` file of one of its processes.
+fn container_cgroup(
+    container_id: &str,
+    proc_root: &Path,
+    cgroup_root: &Path,
+) -> io::Result<PathBuf> {
+    for entry in fs::read_dir(proc_root)? {
+        let entry = entry?;
+        if entry.file_name().to_string_lossy().parse::<u32>().is_err() {
+            continue;
+        }
+        // Processes may exit while being scanned
+        let Ok(cgroup) = fs::read_to_string(entry.path().join("cgroup")) else {
+            continue;
+        };
+        if !resource::container_id(&cgroup).is_some_and(|id| id.starts_with(container_id)) {
+            continue;
+        }
+        // The unified hierarchy is listed as `0::<path>`
+        if let Some(path) = cgroup.lines().find_map(|line| line.strip_prefix("0::")) {
+            return Ok(cgroup_root.join(path.trim_start_matches('/')));
+        }
+    }
+    Err(io::Error::new(
+        io::ErrorKind::NotFound,
+        format!("No process found in container {}", container_id),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::process::ProcessConfig;
+
+    const ID: &str = "3f4b1c1e0c5a9f2d8e7b6a5c4d3e2f1a0b9c8d7e6f5a4b3c2d1e0f9a8b7c6d5e";
+
+    #[test]
+    fn test_single_pid_is_used_for_attachment() {
+        let filter = FilterConfig {
+            pids: vec![4242],
+            ..Default::default()
+        };
+        assert_eq!(
+            filter.resolve().unwrap(),
+            ResolvedFilter {
+                attach_pid: Some(4242),
+                tgids: vec![4242],
+                ..Default::default()
+            }
+        );
+
+        let filter = FilterConfig {
+            pids: vec![4242, 4243],
+            ..Default::default()
+        };
+        assert_eq!(
+            filter.resolve().unwrap(),
+            ResolvedFilter {
+                tgids: vec![4242, 4243],
+                ..Default::default()
+            }
+        );
+    }
+
+    #[test]
+    fn test_single_pid_filter_excludes_other_processes() {
+        let filter = FilterConfig {
+            pids: vec![4242],
+            ..Default::default()
+        };
+        let resolved = filter.resolve().unwrap();
+        let process: ProcessConfig =
+            serde_json::from_str(r#"{"pid": 1000, "functions": ["handle"]}"#).unwrap();
+        for pid in process.matching_pids().unwrap() {
+            assert!(!resolved.attaches_to(pid));
+        }
+        assert!(resolved.attaches_to(4242));
+        assert!(ResolvedFilter::default().attaches_to(1000));
+    }
+
+    #[test]
+    fn test_container_id_resolves_to_its_cgroup() {
+        let root = std::env::temp_dir().join("filter_test_container");
+        let _ = fs::remove_dir_all(&root);
+        let proc_root = root.join("proc");
+        let cgroup_root = root.join("cgroup");
+        let scope = cgroup_root.join(format!("system.slice/docker-{}.scope", ID));
+        fs::create_dir_all(proc_root.join("100")).unwrap();
+        fs::create_dir_all(proc_root.join("self")).unwrap();
+        fs::create_dir_all(&scope).unwrap();
+        fs::write(
+            proc_root.join("100/cgroup"),
+            format!("0::/system.slice/docker-{}.scope\n", ID),
+        )
+        .unwrap();
+
+        let filter = FilterConfig {
+            container_id: Some(ID[..12].to_string()),
+            cgroup_path: Some("/system.slice".to_string()),
+            ..Default::default()
+        };
+        filter.validate().unwrap();
+        let resolved = filter.resolve_in(&proc_root, &cgroup_root).unwrap();
+        assert_eq!(resolved.attach_pid, None);
+        assert_eq!(
+            resolved.cgroup_ids,
+            vec![
+                cgroup_id(&cgroup_root.join("system.slice")).unwrap(),
+                cgroup_id(&scope).unwrap()
+            ]
+        );
+
+        let missing = FilterConfig {
+            container_id: Some("0123456789ab".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(
+            missing
+                .resolve_in(&proc_root, &cgroup_root)
+                .unwrap_err()
+                .kind(),
+            io::ErrorKind::NotFound
+        );
+    }
+
+    #[test]
+    fn test_invalid_filters_are_rejected() {
+        for filter in [
+            FilterConfig {
+                pids: vec![0],
+                ..Default::default()
+            },
+            FilterConfig {
+                container_id: Some("my-container".to_string()),
+                ..Default::default()
+            },
+        ] {
+            assert_eq!(
+                filter.validate().unwrap_err().kind(),
+                io::ErrorKind::InvalidInput
+            );
+        }
+    }
+}
@@ -1,54 +1,181 @@
 use crate::{
-    controller::Controller, manager::Manager, offset_tracker::OffsetTracker, probe::Probe,
+    controller::Controller,
+    manager::Manager,
+    metrics::FunctionMetrics,
+    offset_tracker::{InstrumentationConfig, OffsetTracker},
+    registry::FunctionRegistry,
+    reload::{AttachedFunction, ConfigReloader},
+    resource::ProcessTracers,
+    tail_sampling::TailSampler,
+    watcher::ProcessWatcher,
 };
 use anyhow::Result;
-use std::{collections::HashMap, sync::Arc};
-use tokio::sync::{mpsc, Mutex};
+use std::collections::HashMap;
+use std::sync::Arc;
+use tokio::signal::unix::Signal;
+use tokio::sync::{mpsc, oneshot};
 
 pub struct Instrumentation {
     manager: Manager,
-    controller: Arc<Mutex<Controller>>,
+    controller: Option<Controller>,
+    watcher: Option<ProcessWatcher>,
+    reloader: Option<ConfigReloader>,
 }
 
 impl Instrumentation {
-    pub fn new(offset_tracker: &OffsetTracker) -> Result<Self> {
+    /// `config` was read from `config_path`, which is reloaded when it changes
+    /// or `hangup` receives SIGHUP.
+    pub fn new(
+        config_path: &str,
+        config: &InstrumentationConfig,
+        offset_tracker: &OffsetTracker,
+        tracers: ProcessTracers,
+        metrics: Option<Arc<FunctionMetrics>>,
+        hangup: Signal,
+    ) -> Result<Self> {
+        let tail_sampling = &config.tail_sampling;
         println!("Initializing instrumentation...");
         let (event_sender, event_receiver) = mpsc::channel(100);
-        let controller = Arc::new(Mutex::new(Controller::new(event_receiver)?));
-        let mut manager = Manager::new(controller.clone())?;
+        let registry = FunctionRegistry::new();
+        let controller = Controller::new(
+            event_receiver,
+            registry.clone(),
+            tracers,
+            metrics.clone(),
+            tail_sampling
+                .enabled
+                .then(|| TailSampler::new(tail_sampling.clone())),
+        )?;
+        let mut manager = Manager::new(
+            event_sender,
+            registry,
+            metrics,
+            config.events.ring_buffer_size(),
+        )?;
+        let filter = if config.filter.is_empty() {
+            None
+        } else {
+            let resolved = config.filter.resolve()?;
+            println!("Tracing only processes matching {:?}", resolved);
+            manager.set_filter(&resolved)?;
+            Some(resolved)
+        };
 
+        let mut attached = HashMap::new();
         for (binary, functions) in &offset_tracker.offsets {
             for (demangled_name, function_info) in functions.iter() {
                 println!(
                     "🔍 Attaching probe to {} in {} at {:#x}",
                     demangled_name, binary, function_info.offset
                 );
-                let probe = Probe::new(
-                    binary,
-                    &function_info.mangled_name, // Use mangled name for probe
-                    event_sender.clone(),
-                    function_info.offset,
-                )?;
-                manager.register_probe(probe);
+                let function_id = manager.register_probe(binary, function_info, None)?;
+                attached.insert(
+                    (binary.clone(), demangled_name.clone()),
+                    AttachedFunction {
+                        function_id,
+                        info: function_info.clone(),
+                    },
+                );
             }
         }
+        let mut process_probes: HashMap<u32, Vec<u64>> = HashMap::new();
+        for (pid, process) in &offset_tracker.processes {
+            // Known to the watcher even if excluded, so it is not retried
+            process_probes.entry(*pid).or_default();
+            if filter
+                .as_ref()
+                .is_some_and(|filter| !filter.attaches_to(*pid))
+            {
+                println!("Skipping pid {}, which is excluded by the filter", pid);
+                continue;
+            }
+            for (object, functions) in &process.objects {
+                for (demangled_name, function_info) in functions {
+                    println!(
+                        "🔍 Attaching probe to {} in {} of pid {} at {:#x}",
+                        demangled_name, object, pid, function_info.offset
+                    );
+                    match manager.register_probe(object, function_info, Some(*pid)) {
+                        Ok(function_id) => {
+                            process_probes.entry(*pid).or_default().push(function_id)
+                        }
+                        Err(err) => eprintln!(
+                            "⚠️ Failed to attach {} in {} of pid {}: {}",
+                            demangled_name, object, pid, err
+                        ),
+                    }
+                }
+            }
+        }
+        let watcher = config.watch.enabled.then(|| {
+            ProcessWatcher::new(
+                &config.watch,
+                config.processes.clone(),
+                config.debug_info.clone(),
+                process_probes,
+            )
+        });
+
+        let reloader = ConfigReloader::new(config_path, config.clone(), attached, hangup);
 
         Ok(Self {
             manager,
-            controller,
+            controller: Some(controller),
+            watcher,
+            reloader: Some(reloader),
         })
     }
 
-    pub async fn run(&self) -> Result<()> {
+    /// Runs until Ctrl-C, then detaches the probes and waits for the
+    /// controller to export what it still holds. The tracers can be shut down
+    /// once this returns.
+    pub async fn run(&mut self) -> Result<()> {
         println!("Running instrumentation...");
-        let controller = self.controller.clone();
-        tokio::spawn(async move {
-            let mut controller_guard = controller.lock().await;
-            if let Err(err) = controller_guard.run().await {
-                eprintln!("Controller error: {}", err);
-            }
+        let (stop_controller, controller_stopped) = oneshot::channel();
+        let controller = self.controller.take().map(|mut controller| {
+            tokio::spawn(async move {
+                if let Err(err) = controller.run(controller_stopped).await {
+                    eprintln!("Controller error: {}", err);
+                }
+            })
+        });
+        let watcher = self.watcher.take().map(|watcher| {
+            let manager = self.manager.handle();
+            tokio::spawn(async move {
+                if let Err(err) = watcher.run(manager).await {
+                    eprintln!("Process watcher error: {}", err);
+                }
+            })
         });
 
-        self.manager.run().await
+        let reloader = self.reloader.take().map(|reloader| {
+            let manager = self.manager.handle();
+            tokio::spawn(async move {
+                if let Err(err) = reloader.run(manager).await {
+                    eprintln!("Config reloader error: {}", err);
+                }
+            })
+        });
+
+        let result = self
+            .manager
+            .run(async {
+                let _ = tokio::signal::ctrl_c().await;
+                println!("Received Ctrl-C, shutting down...");
+            })
+            .await;
+
+        for task in [watcher, reloader].into_iter().flatten() {
+            task.abort();
+        }
+        let detached = self.manager.detach_all();
+
+        let _ = stop_controller.send(());
+        if let Some(controller) = controller {
+            if let Err(err) = controller.await {
+                eprintln!("Controller error: {}", err);
+            }
+        }
+        result.and(detached)
     }
 }
@@ -1,27 +1,37 @@
+mod aggregation;
+mod capture;
+mod clock;
 mod controller;
+mod debuginfo;
+#[cfg(feature = "debuginfod")]
+mod debuginfod;
+mod exporter;
+mod filter;
 mod instrumentation;
 mod manager;
+mod metrics;
 mod offset_tracker;
 mod probe;
+mod process;
+mod registry;
+mod reload;
+mod resource;
+mod sampling;
+mod selector;
+mod tail_sampling;
+mod watcher;
 
 use anyhow::Result;
 use instrumentation::Instrumentation;
-use offset_tracker::OffsetTracker;
-use opentelemetry::global;
-use opentelemetry_sdk::trace::TracerProvider as SdkTracerProvider;
-use std::{env, fs, thread::sleep, time::Duration};
+use metrics::FunctionMetrics;
+use offset_tracker::{InstrumentationConfig, OffsetTracker};
+use resource::ProcessTracers;
+use std::{env, fs, sync::Arc, thread::sleep, time::Duration};
+use tokio::signal::unix::{signal, SignalKind};
 use tracing::info;
 
 #[tokio::main(flavor = "multi_thread")] // ✅ Multi-threaded runtime
 async fn main() -> Result<()> {
-    let exporter = opentelemetry_stdout::SpanExporter::default();
-    let provider = SdkTracerProvider::builder()
-        .with_simple_exporter(exporter)
-        .build();
-    global::set_tracer_provider(provider);
-    info!("OpenTelemetry tracing initialized with stdout exporter");
-    sleep(Duration::from_secs(5));
-
     let args: Vec<String> = env::args().collect();
     let config_path = args
         .get(1)
@@ -35,7 +45,7 @@ async fn main() -> Result<()> {
         });
 
     // Check if the file exists and is readable
-    if !fs::metadata(&config_path).is_ok() {
+    if fs::metadata(&config_path).is_err() {
         eprintln!(
             "❌ Error: Configuration file '{}' does not exist or is not readable.",
             config_path
@@ -44,19 +54,50 @@ async fn main() -> Result<()> {
     }
 
     println!("Using config file: {}", config_path);
-    let offset_tracker = OffsetTracker::from_config_file(config_path)?;
+    let config = InstrumentationConfig::from_file(&config_path)?;
+    // Installed before any probe is attached: until then SIGHUP would
+    // terminate the agent instead of reloading the config
+    let hangup = signal(SignalKind::hangup())?;
+
+    let offset_tracker = OffsetTracker::from_config(&config)?;
     println!("offset traceker initialized");
 
+    let tracers = ProcessTracers::new(config.exporter.clone());
+    info!(
+        "OpenTelemetry tracing initialized with {:?} exporter",
+        config.exporter.protocol
+    );
+    sleep(Duration::from_secs(5));
+
     for (binary, functions) in &offset_tracker.offsets {
-        for (function, function_info) in functions {
+        for function_info in functions.values() {
             println!(
-                "✅ Found function offset for '{}' in '{}': {:#x}",
-                function_info.demangled_name, binary, function_info.offset
+                "✅ Found function offset for '{}' in '{}': {:#x} (address {:#x})",
+                function_info.demangled_name, binary, function_info.offset, function_info.address
             );
         }
     }
 
-    let instrumentation = Instrumentation::new(&offset_tracker)?;
-    instrumentation.run().await?;
-    Ok(())
+    let metrics = if config.metrics.enabled {
+        Some(Arc::new(FunctionMetrics::new(&config.metrics)?))
+    } else {
+        None
+    };
+
+    let mut instrumentation = Instrumentation::new(
+        &config_path,
+        &config,
+        &offset_tracker,
+        tracers.clone(),
+        metrics.clone(),
+        hangup,
+    )?;
+    let result = instrumentation.run().await;
+
+    // Flush spans still queued in the batch processors, and the last metrics
+    tracers.shutdown();
+    if let Some(metrics) = metrics {
+        metrics.shutdown();
+    }
+    result
 }
@@ -1,47 +1,408 @@
-use crate::controller::Controller;
-use crate::probe::Probe;
+use crate::aggregation;
+use crate::capture;
+use crate::filter::ResolvedFilter;
+use crate::metrics::FunctionMetrics;
+use crate::offset_tracker::FunctionInfo;
+use crate::probe::{self, BPFEvent, Probe};
+use crate::registry::FunctionRegistry;
+use crate::sampling::SampleRate;
 use anyhow::Result;
+use libbpf_rs::{Map, MapCore, MapFlags, MapHandle, Object};
+use std::future::{self, Future};
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
-use tokio::sync::Mutex;
-use tokio::task::{spawn_local, LocalSet};
+use std::time::Duration;
+use tokio::sync::mpsc::{self, Receiver, Sender};
+use tokio::sync::oneshot;
+
+/// Changes to the attached probes, sent to a running `Manager` through a
+/// `ManagerHandle`. Each carries a channel for the outcome.
+pub enum ManagerCommand {
+    Register {
+        binary_path: String,
+        function_info: Box<FunctionInfo>,
+        pid: Option<u32>,
+        reply: oneshot::Sender<Result<u64>>,
+    },
+    Unregister {
+        function_id: u64,
+        reply: oneshot::Sender<Result<()>>,
+    },
+    SetSampleRate {
+        function_id: u64,
+        rate: SampleRate,
+        reply: oneshot::Sender<Result<()>>,
+    },
+}
+
+/// Sends commands to a `Manager`. They are applied while it runs, between
+/// other work, and each call waits for the outcome.
+#[derive(Clone)]
+pub struct ManagerHandle {
+    commands: Sender<ManagerCommand>,
+}
+
+impl ManagerHandle {
+    /// Attaches a probe and returns the new function id.
+    pub async fn register_probe(
+        &self,
+        binary_path: &str,
+        function_info: FunctionInfo,
+        pid: Option<u32>,
+    ) -> Result<u64> {
+        self.request(|reply| ManagerCommand::Register {
+            binary_path: binary_path.to_string(),
+            function_info: Box::new(function_info),
+            pid,
+            reply,
+        })
+        .await
+    }
+
+    pub async fn unregister_probe(&self, function_id: u64) -> Result<()> {
+        self.request(|reply| ManagerCommand::Unregister { function_id, reply })
+            .await
+    }
+
+    pub async fn set_sample_rate(&self, function_id: u64, rate: SampleRate) -> Result<()> {
+        self.request(|reply| ManagerCommand::SetSampleRate {
+            function_id,
+            rate,
+            reply,
+        })
+        .await
+    }
+
+    async fn request<T>(
+        &self,
+        command: impl FnOnce(oneshot::Sender<Result<T>>) -> ManagerCommand,
+    ) -> Result<T> {
+        let (reply, outcome) = oneshot::channel();
+        self.commands
+            .send(command(reply))
+            .await
+            .map_err(|_| anyhow::anyhow!("Manager is not running"))?;
+        outcome
+            .await
+            .map_err(|_| anyhow::anyhow!("Manager stopped before replying"))?
+    }
+}
+
+/// How often events dropped because the ring buffer was full are reported.
+const DROPPED_EVENTS_REPORT_INTERVAL: Duration = Duration::from_secs(10);
 
 pub struct Manager {
-    probes: Vec<Arc<Probe>>,
-    controller: Arc<Mutex<Controller>>,
+    bpf_object: Object,
+    probes: Vec<Probe>,
+    event_channel: Sender<BPFEvent>,
+    registry: FunctionRegistry,
+    /// Where in-kernel aggregates are published, if metrics are enabled.
+    metrics: Option<Arc<FunctionMetrics>>,
+    /// Process probes are attached to; every process when `None`.
+    attach_pid: Option<u32>,
+    commands: Receiver<ManagerCommand>,
+    /// Kept so `handle()` can be called at any time; the command channel
+    /// never closes while the manager exists.
+    command_sender: Sender<ManagerCommand>,
+    next_function_id: u64,
 }
-use tokio::task;
 
 impl Manager {
-    pub fn new(controller: Arc<Mutex<Controller>>) -> Result<Self> {
+    /// `ring_buffer_size` is the size of the events ring buffer in bytes.
+    pub fn new(
+        event_channel: Sender<BPFEvent>,
+        registry: FunctionRegistry,
+        metrics: Option<Arc<FunctionMetrics>>,
+        ring_buffer_size: u32,
+    ) -> Result<Self> {
+        let (command_sender, commands) = mpsc::channel(16);
         Ok(Self {
+            bpf_object: probe::load_bpf_object(ring_buffer_size)?,
             probes: Vec::new(),
-            controller,
+            event_channel,
+            registry,
+            metrics,
+            attach_pid: None,
+            commands,
+            command_sender,
+            next_function_id: 1,
         })
     }
 
-    pub fn register_probe(&mut self, probe: Probe) {
-        self.probes.push(Arc::new(probe));
+    /// Attaches a probe for `function_info` using the shared BPF object and
+    /// returns the function id events from it will carry. With a `pid`, only
+    /// calls made by that process are seen. The filter applies either way, and
+    /// a `pid` other than the single process it traces is refused.
+    pub fn register_probe(
+        &mut self,
+        binary_path: &str,
+        function_info: &FunctionInfo,
+        pid: Option<u32>,
+    ) -> Result<u64> {
+        if let (Some(pid), Some(attach_pid)) = (pid, self.attach_pid) {
+            if pid != attach_pid {
+                return Err(anyhow::anyhow!(
+                    "Process {} is excluded by the filter, which only traces process {}",
+                    pid,
+                    attach_pid
+                ));
+            }
+        }
+        let function_id = self.next_function_id;
+        let mut flags = 0;
+        if function_info.aggregate {
+            if self.metrics.is_none() {
+                eprintln!(
+                    "⚠️ {} is aggregated, but metrics are disabled; its calls will not be reported",
+                    function_info.demangled_name
+                );
+            }
+            flags |= capture::FUNCTION_FLAG_AGGREGATE;
+        }
+        if self.metrics.is_some() {
+            flags |= capture::FUNCTION_FLAG_COUNT;
+        }
+
+        // The entry program reads this on every call, so it must be in place
+        // before the probe is attached.
+        let function_configs = self.map("function_configs")?;
+        function_configs.update(
+            &function_id.to_ne_bytes(),
+            &capture::encode_function_config(
+                &function_info.arguments,
+                function_info.return_value.as_ref(),
+                flags,
+            ),
+            MapFlags::ANY,
+        )?;
+        self.write_sample_rate(function_id, function_info.sample_rate.unwrap_or_default())?;
+
+        let probe = match Probe::new(
+            &self.bpf_object,
+            pid.or(self.attach_pid),
+            binary_path,
+            &function_info.mangled_name, // Use mangled name for probe
+            function_info.offset,
+            function_id,
+        ) {
+            Ok(probe) => probe,
+            Err(err) => {
+                self.forget_function(function_id);
+                return Err(err);
+            }
+        };
+        self.next_function_id += 1;
+        self.registry
+            .insert(function_id, binary_path, function_info);
+        self.probes.push(probe);
+        Ok(function_id)
+    }
+
+    /// Restricts tracing to the processes selected by `filter`. The allowlists
+    /// apply at once to every probe; attaching to a single process only
+    /// applies to probes registered afterwards.
+    pub fn set_filter(&mut self, filter: &ResolvedFilter) -> Result<()> {
+        const FILTER_TGID: u32 = 1 << 0;
+        const FILTER_CGROUP: u32 = 1 << 1;
+
+        if filter.attach_pid.is_some() {
+            self.attach_pid = filter.attach_pid;
+        }
+
+        let mut flags = 0;
+        let allowed_tgids = self.map("allowed_tgids")?;
+        for tgid in &filter.tgids {
+            allowed_tgids.update(&tgid.to_ne_bytes(), &[1], MapFlags::ANY)?;
+            flags |= FILTER_TGID;
+        }
+        let allowed_cgroups = self.map("allowed_cgroups")?;
+        for cgroup_id in &filter.cgroup_ids {
+            allowed_cgroups.update(&cgroup_id.to_ne_bytes(), &[1], MapFlags::ANY)?;
+            flags |= FILTER_CGROUP;
+        }
+        self.map("filter_flags")?.update(
+            &0u32.to_ne_bytes(),
+            &flags.to_ne_bytes(),
+            MapFlags::ANY,
+        )?;
+        Ok(())
+    }
+
+    pub fn handle(&self) -> ManagerHandle {
+        ManagerHandle {
+            commands: self.command_sender.clone(),
+        }
+    }
+
+    /// Detaches the probe of `function_id` and forgets the function.
+    pub fn unregister_probe(&mut self, function_id: u64) -> Result<()> {
+        let index = self
+            .probes
+            .iter()
+            .position(|probe| probe.function_id == function_id)
+            .ok_or_else(|| anyhow::anyhow!("No probe with function id {}", function_id))?;
+        let mut probe = self.probes.remove(index);
+        let result = probe.detach();
+        self.forget_function(function_id);
+        result
+    }
+
+    /// Removes the per-function BPF state and registry entry of a function
+    /// without an attached probe.
+    fn forget_function(&self, function_id: u64) {
+        for name in ["function_configs", "sample_thresholds", "call_stats"] {
+            if let Ok(map) = self.map(name) {
+                let _ = map.delete(&function_id.to_ne_bytes());
+            }
+        }
+        self.registry.remove(function_id);
+    }
+
+    /// Changes the share of calls of `function_id` that produce events. Takes
+    /// effect on the next call, without reattaching the probe.
+    pub fn set_sample_rate(&self, function_id: u64, rate: SampleRate) -> Result<()> {
+        if self.registry.get(function_id).is_none() {
+            return Err(anyhow::anyhow!("No probe with function id {}", function_id));
+        }
+        self.write_sample_rate(function_id, rate)
+    }
+
+    fn write_sample_rate(&self, function_id: u64, rate: SampleRate) -> Result<()> {
+        rate.validate()?;
+        self.map("sample_thresholds")?.update(
+            &function_id.to_ne_bytes(),
+            &rate.threshold().to_ne_bytes(),
+            MapFlags::ANY,
+        )?;
+        Ok(())
+    }
+
+    /// Probes whose entry and return links are currently attached.
+    pub fn attached_probes(&self) -> impl Iterator<Item = &Probe> {
+        self.probes.iter().filter(|probe| probe.is_attached())
     }
 
-    pub async fn run(&self) -> Result<()> {
-        println!("Running manager...");
-        let local_set = LocalSet::new(); // ✅ Create a `LocalSet`
+    /// Detaches every registered probe, reporting the first failure.
+    pub fn detach_all(&mut self) -> Result<()> {
+        let mut result = Ok(());
+        for probe in &mut self.probes {
+            if let Err(err) = probe.detach() {
+                eprintln!("Failed to detach probe {}: {}", probe.function_id, err);
+                if result.is_ok() {
+                    result = Err(err);
+                }
+            }
+        }
+        result
+    }
 
+    /// Forwards events and applies commands until `shutdown` completes or event
+    /// polling fails. The ring buffer poller has exited when this returns.
+    pub async fn run(&mut self, shutdown: impl Future<Output = ()>) -> Result<()> {
+        println!(
+            "Running manager with {} attached probes...",
+            self.attached_probes().count()
+        );
         for probe in &self.probes {
-            println!("Iterating over probes...");
-            let probe = Arc::clone(probe);
-            local_set.spawn_local(async move {
-                println!("Spawning probe run...");
-
-                if let Err(e) = probe.run().await {
-                    eprintln!("Probe run failed: {}", e);
-                } else {
-                    println!("Probe run initiated.");
+            println!(
+                "- [{}] {} in {} (pid {}): {:?}",
+                probe.function_id,
+                probe.function_name,
+                probe.binary_path,
+                probe.pid.map_or("any".to_string(), |pid| pid.to_string()),
+                probe.status()
+            );
+        }
+
+        let events_map = MapHandle::try_from(&self.map("events")?)?;
+
+        let dropped_events_map = MapHandle::try_from(&self.map("dropped_events")?)?;
+
+        let event_channel = self.event_channel.clone();
+        let stop = Arc::new(AtomicBool::new(false));
+        let poller_stop = stop.clone();
+        let mut events = tokio::task::spawn_blocking(move || {
+            probe::poll_events(events_map, event_channel, poller_stop)
+        });
+
+        let aggregation = match self.metrics.clone() {
+            Some(metrics) => Some(aggregation::run(
+                MapHandle::try_from(&self.map("call_stats")?)?,
+                self.registry.clone(),
+                metrics.clone(),
+                metrics.aggregation_interval(),
+            )),
+            None => None,
+        };
+        let aggregation = async move {
+            match aggregation {
+                Some(aggregation) => aggregation.await,
+                None => future::pending().await,
+            }
+        };
+        tokio::pin!(aggregation);
+
+        let mut dropped_events_report = tokio::time::interval(DROPPED_EVENTS_REPORT_INTERVAL);
+        let mut reported_drops = 0;
+        tokio::pin!(shutdown);
+
+        let result = loop {
+            tokio::select! {
+                result = &mut events => return result?,
+                result = &mut aggregation => break result,
+                _ = &mut shutdown => break Ok(()),
+                _ = dropped_events_report.tick() => {
+                    let dropped = match probe::dropped_events(&dropped_events_map) {
+                        Ok(dropped) => dropped,
+                        Err(err) => break Err(err),
+                    };
+                    if dropped > reported_drops {
+                        eprintln!(
+                            "⚠️ Dropped {} events because the ring buffer was full; consider raising events.ring_buffer_size_mb",
+                            dropped - reported_drops
+                        );
+                        reported_drops = dropped;
+                    }
                 }
-            });
+                Some(command) = self.commands.recv() => self.apply(command),
+            }
+        };
+
+        // The poller checks the flag between polls. It must be joined here:
+        // the runtime waits for blocking tasks when it shuts down.
+        stop.store(true, Ordering::Relaxed);
+        let polled = events.await?;
+        result.and(polled)
+    }
+
+    fn apply(&mut self, command: ManagerCommand) {
+        // A requester that stopped waiting is not an error
+        match command {
+            ManagerCommand::Register {
+                binary_path,
+                function_info,
+                pid,
+                reply,
+            } => {
+                let _ = reply.send(self.register_probe(&binary_path, &function_info, pid));
+            }
+            ManagerCommand::Unregister { function_id, reply } => {
+                let _ = reply.send(self.unregister_probe(function_id));
+            }
+            ManagerCommand::SetSampleRate {
+                function_id,
+                rate,
+                reply,
+            } => {
+                let _ = reply.send(self.set_sample_rate(function_id, rate));
+            }
         }
-        local_set.await; // ✅ Ensure `spawn_local()` tasks are executed
+    }
 
-        Ok(())
+    fn map(&self, name: &str) -> Result<Map<'_>> {
+        self.bpf_object
+            .maps()
+            .find(|m| m.name().to_string_lossy().as_ref() == name)
+            .ok_or_else(|| anyhow::anyhow!("{} map not found", name))
     }
 }
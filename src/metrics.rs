@@ -0,0 +1,398 @@
+use crate::aggregation::CallStats;
+use crate::exporter::{self, ExporterConfig};
+use crate::registry::RegisteredFunction;
+use crate::resource;
+use anyhow::Result;
+use opentelemetry::metrics::{Counter, Histogram, MeterProvider as _};
+use opentelemetry::KeyValue;
+use opentelemetry_sdk::metrics::{new_view, Aggregation, Instrument, SdkMeterProvider, Stream};
+use opentelemetry_sdk::Resource;
+use serde::{Deserialize, Serialize};
+use std::io;
+use std::time::Duration;
+
+/// Name of the call duration histogram of sampled calls, in seconds.
+pub const DURATION_HISTOGRAM: &str = "function.call.duration";
+/// Name of the counter of all calls, sampled or not.
+pub const CALL_COUNTER: &str = "function.calls";
+/// Total time spent in all calls, in seconds.
+pub const CALL_TIME_COUNTER: &str = "function.call.time";
+/// All calls per log2 latency bucket.
+pub const LATENCY_BUCKET_COUNTER: &str = "function.call.latency.log2";
+
+fn default_export_interval_secs() -> u64 {
+    60
+}
+
+fn default_aggregation_interval_secs() -> u64 {
+    10
+}
+
+/// From 1µs to 10s.
+fn default_duration_buckets() -> Vec<f64> {
+    vec![
+        0.000_001, 0.000_005, 0.000_01, 0.000_05, 0.000_1, 0.000_5, 0.001, 0.005, 0.01, 0.05, 0.1,
+        0.5, 1.0, 5.0, 10.0,
+    ]
+}
+
+/// Per-function call metrics. Every call is counted in the kernel, in
+/// `function.calls`, `function.call.time` and `function.call.latency.log2`;
+/// the `function.call.duration` histogram only sees head-sampled calls.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MetricsConfig {
+    #[serde(default)]
+    pub enabled: bool,
+    /// Where metrics are pushed, independently of the span exporter.
+    #[serde(default)]
+    pub exporter: ExporterConfig,
+    #[serde(default = "default_export_interval_secs")]
+    pub export_interval_secs: u64,
+    /// Upper bounds of the buckets of the sampled call duration histogram,
+    /// in seconds.
+    #[serde(default = "default_duration_buckets")]
+    pub duration_buckets: Vec<f64>,
+    /// How often the in-kernel call counts are read.
+    #[serde(default = "default_aggregation_interval_secs")]
+    pub aggregation_interval_secs: u64,
+    /// `service.name` of the metrics resource. Defaults to the agent's name;
+    /// the functions' binaries are told apart by the `binary.path` attribute.
+    #[serde(default)]
+    pub service_name: Option<String>,
+}
+
+impl Default for MetricsConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            exporter: ExporterConfig::default(),
+            export_interval_secs: default_export_interval_secs(),
+            duration_buckets: default_duration_buckets(),
+            aggregation_interval_secs: default_aggregation_interval_secs(),
+            service_name: None,
+        }
+    }
+}
+
+impl MetricsConfig {
+    pub fn validate(&self) -> io::Result<()> {
+        if self.export_interval_secs == 0 || self.aggregation_interval_secs == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "metrics intervals must be positive",
+            ));
+        }
+        if !self
+            .duration_buckets
+            .windows(2)
+            .all(|pair| pair[0] < pair[1])
+        {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "metrics duration_buckets must be strictly increasing",
+            ));
+        }
+        self.exporter.validate()
+    }
+}
+
+/// Duration histogram and call counter shared by all instrumented functions,
+/// told apart by their attributes. Aggregated functions, whose individual
+/// durations are not known, report a total time and log2 bucket counts
+/// instead of the histogram.
+pub struct FunctionMetrics {
+    provider: SdkMeterProvider,
+    duration: Histogram<f64>,
+    calls: Counter<u64>,
+    call_time: Counter<f64>,
+    latency_buckets: Counter<u64>,
+    aggregation_interval: Duration,
+}
+
+impl FunctionMetrics {
+    /// Must be called inside the Tokio runtime.
+    pub fn new(config: &MetricsConfig) -> Result<Self> {
+        config.validate()?;
+        let duration_view = new_view(
+            Instrument::new().name(DURATION_HISTOGRAM),
+            Stream::new().aggregation(Aggregation::ExplicitBucketHistogram {
+                boundaries: config.duration_buckets.clone(),
+                record_min_max: true,
+            }),
+        )?;
+        let provider = exporter::build_meter_provider(
+            &config.exporter,
+            Duration::from_secs(config.export_interval_secs),
+            vec![duration_view],
+            metrics_resource(config, resource::host_name().as_deref()),
+        )?;
+        Ok(Self::with_provider(
+            provider,
+            Duration::from_secs(config.aggregation_interval_secs),
+        ))
+    }
+
+    fn with_provider(provider: SdkMeterProvider, aggregation_interval: Duration) -> Self {
+        let meter = provider.meter("ebpf_tracer");
+        let duration = meter
+            .f64_histogram(DURATION_HISTOGRAM)
+            .with_unit("s")
+            .with_description(
+                "Duration of head-sampled calls to instrumented functions; see function.call.latency.log2 for all calls",
+            )
+            .build();
+        let calls = meter
+            .u64_counter(CALL_COUNTER)
+            .with_description("Calls to instrumented functions")
+            .build();
+        let call_time = meter
+            .f64_counter(CALL_TIME_COUNTER)
+            .with_unit("s")
+            .with_description("Time spent in instrumented functions")
+            .build();
+        let latency_buckets = meter
+            .u64_counter(LATENCY_BUCKET_COUNTER)
+            .with_description(
+                "Calls to instrumented functions taking [2^latency.log2_ns, 2^(latency.log2_ns+1)) ns",
+            )
+            .build();
+        Self {
+            provider,
+            duration,
+            calls,
+            call_time,
+            latency_buckets,
+            aggregation_interval,
+        }
+    }
+
+    /// How often in-kernel aggregates should be read and published.
+    pub fn aggregation_interval(&self) -> Duration {
+        self.aggregation_interval
+    }
+
+    /// Records the duration of a sampled call. Calls are counted in the
+    /// kernel and published by `record_aggregated`, so they are not counted
+    /// here.
+    pub fn record(&self, function: &RegisteredFunction, duration: Duration, error: bool) {
+        let mut attributes = function_attributes(function);
+        attributes.push(KeyValue::new("error", error));
+
+        self.duration.record(duration.as_secs_f64(), &attributes);
+    }
+
+    /// Publishes calls counted in the kernel: every call, whether head
+    /// sampling kept it or not.
+    pub fn record_aggregated(&self, function: &RegisteredFunction, stats: &CallStats) {
+        let attributes = function_attributes(function);
+
+        for (error, count) in [(false, stats.count - stats.errors), (true, stats.errors)] {
+            if count == 0 {
+                continue;
+            }
+            let mut call_attributes = attributes.clone();
+            call_attributes.push(KeyValue::new("error", error));
+            self.calls.add(count, &call_attributes);
+        }
+        self.call_time
+            .add(stats.total_ns as f64 / 1_000_000_000.0, &attributes);
+        for (bucket, &count) in stats.latency.iter().enumerate() {
+            if count == 0 {
+                continue;
+            }
+            let mut bucket_attributes = attributes.clone();
+            bucket_attributes.push(KeyValue::new("latency.log2_ns", bucket as i64));
+            self.latency_buckets.add(count, &bucket_attributes);
+        }
+    }
+
+    /// Exports what was recorded since the last interval and stops the reader.
+    pub fn shutdown(&self) {
+        if let Err(err) = self.provider.shutdown() {
+            eprintln!("Failed to shut down meter provider: {}", err);
+        }
+    }
+}
+
+/// Resource of the meter provider, with the same `service.name` and
+/// `host.name` keys as the per-process trace resources.
+fn metrics_resource(config: &MetricsConfig, host_name: Option<&str>) -> Resource {
+    let service_name = config
+        .service_name
+        .clone()
+        .unwrap_or_else(|| env!("CARGO_PKG_NAME").to_string());
+    let mut attributes = vec![KeyValue::new("service.name", service_name)];
+    if let Some(host_name) = host_name {
+        attributes.push(KeyValue::new("host.name", host_name.to_string()));
+    }
+    Resource::new_with_defaults(attributes)
+}
+
+fn function_attributes(function: &RegisteredFunction) -> Vec<KeyValue> {
+    let mut attributes = vec![
+        KeyValue::new("code.function", function.code_function().to_string()),
+        KeyValue::new("binary.path", function.binary_path.clone()),
+    ];
+    if let Some(namespace) = function.code_namespace() {
+        attributes.push(KeyValue::new("code.namespace", namespace.to_string()));
+    }
+    attributes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::aggregation::LATENCY_BUCKETS;
+    use crate::offset_tracker::FunctionInfo;
+    use opentelemetry_sdk::metrics::data::{Histogram as HistogramData, ResourceMetrics, Sum};
+    use opentelemetry_sdk::metrics::reader::MetricReader;
+    use opentelemetry_sdk::metrics::{
+        InstrumentKind, ManualReader, MetricResult, Pipeline, Temporality,
+    };
+    use std::sync::{Arc, Weak};
+
+    /// `ManualReader` is moved into the provider; this keeps a handle to
+    /// collect from.
+    #[derive(Debug, Clone)]
+    struct SharedReader(Arc<ManualReader>);
+
+    impl MetricReader for SharedReader {
+        fn register_pipeline(&self, pipeline: Weak<Pipeline>) {
+            self.0.register_pipeline(pipeline)
+        }
+
+        fn collect(&self, rm: &mut ResourceMetrics) -> MetricResult<()> {
+            self.0.collect(rm)
+        }
+
+        fn force_flush(&self) -> MetricResult<()> {
+            self.0.force_flush()
+        }
+
+        fn shutdown(&self) -> MetricResult<()> {
+            self.0.shutdown()
+        }
+
+        fn temporality(&self, kind: InstrumentKind) -> Temporality {
+            self.0.temporality(kind)
+        }
+    }
+
+    fn function(name: &str) -> RegisteredFunction {
+        RegisteredFunction {
+            binary_path: "/usr/bin/app".to_string(),
+            info: FunctionInfo {
+                demangled_name: name.to_string(),
+                mangled_name: name.to_string(),
+                ..Default::default()
+            },
+        }
+    }
+
+    #[test]
+    fn test_records_duration_and_calls_per_function() {
+        let reader = SharedReader(Arc::new(ManualReader::builder().build()));
+        let view = new_view(
+            Instrument::new().name(DURATION_HISTOGRAM),
+            Stream::new().aggregation(Aggregation::ExplicitBucketHistogram {
+                boundaries: vec![0.001, 0.01],
+                record_min_max: true,
+            }),
+        )
+        .unwrap();
+        let provider = SdkMeterProvider::builder()
+            .with_reader(reader.clone())
+            .with_view(view)
+            .build();
+        let metrics = FunctionMetrics::with_provider(provider, Duration::from_secs(10));
+
+        let query = function("app::db::query");
+        metrics.record(&query, Duration::from_micros(500), false);
+        metrics.record(&query, Duration::from_millis(5), false);
+        metrics.record(&query, Duration::from_millis(50), true);
+        metrics.record_aggregated(
+            &query,
+            &CallStats {
+                count: 5,
+                errors: 2,
+                total_ns: 80_000_000,
+                latency: [0; LATENCY_BUCKETS],
+            },
+        );
+
+        let mut collected = ResourceMetrics {
+            resource: Default::default(),
+            scope_metrics: Vec::new(),
+        };
+        reader.collect(&mut collected).unwrap();
+        let scope_metrics = &collected.scope_metrics[0].metrics;
+
+        let duration = scope_metrics
+            .iter()
+            .find(|metric| metric.name == DURATION_HISTOGRAM)
+            .unwrap();
+        let histogram = duration
+            .data
+            .as_any()
+            .downcast_ref::<HistogramData<f64>>()
+            .unwrap();
+        let ok_point = histogram
+            .data_points
+            .iter()
+            .find(|point| point.attributes.contains(&KeyValue::new("error", false)))
+            .unwrap();
+        assert_eq!(ok_point.bounds, vec![0.001, 0.01]);
+        assert_eq!(ok_point.bucket_counts, vec![1, 1, 0]);
+        assert!(ok_point
+            .attributes
+            .contains(&KeyValue::new("code.function", "query")));
+
+        let calls = scope_metrics
+            .iter()
+            .find(|metric| metric.name == CALL_COUNTER)
+            .unwrap();
+        let sum = calls.data.as_any().downcast_ref::<Sum<u64>>().unwrap();
+        let total: u64 = sum.data_points.iter().map(|point| point.value).sum();
+        assert_eq!(total, 5);
+        let errors = sum
+            .data_points
+            .iter()
+            .find(|point| point.attributes.contains(&KeyValue::new("error", true)))
+            .unwrap();
+        assert_eq!(errors.value, 2);
+    }
+
+    #[test]
+    fn test_metrics_resource_attributes() {
+        let get = |resource: &Resource, key: &'static str| {
+            resource.get(key.into()).map(|value| value.to_string())
+        };
+
+        let resource = metrics_resource(&MetricsConfig::default(), Some("node-1"));
+        assert_eq!(
+            get(&resource, "service.name").as_deref(),
+            Some(env!("CARGO_PKG_NAME"))
+        );
+        assert_eq!(get(&resource, "host.name").as_deref(), Some("node-1"));
+
+        let config = MetricsConfig {
+            service_name: Some("checkout".to_string()),
+            ..Default::default()
+        };
+        let resource = metrics_resource(&config, None);
+        assert_eq!(get(&resource, "service.name").as_deref(), Some("checkout"));
+        assert_eq!(get(&resource, "host.name"), None);
+    }
+
+    #[test]
+    fn test_unsorted_buckets_are_rejected() {
+        let config = MetricsConfig {
+            duration_buckets: vec![0.1, 0.01],
+            ..Default::default()
+        };
+        assert_eq!(
+            config.validate().unwrap_err().kind(),
+            io::ErrorKind::InvalidInput
+        );
+    }
+}
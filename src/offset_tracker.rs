@@ -1,93 +1,350 @@
-use goblin::elf::Elf;
+use crate::capture::{ArgSpec, ReturnConfig, ReturnSpec};
+use crate::debuginfo::{self, DebugInfoConfig};
+use crate::exporter::ExporterConfig;
+use crate::filter::FilterConfig;
+use crate::metrics::MetricsConfig;
+use crate::probe::EventsConfig;
+use crate::process::{self, ProcessConfig};
+use crate::sampling::SampleRate;
+use crate::selector::FunctionSelectors;
+use crate::tail_sampling::TailSamplingConfig;
+use crate::watcher::WatchConfig;
+use goblin::elf::program_header::PT_LOAD;
+use goblin::elf::section_header::SHN_UNDEF;
+use goblin::elf::sym::STT_FUNC;
+use goblin::elf::{Elf, ProgramHeader};
 use regex::Regex;
 use rustc_demangle::demangle;
 use serde::{Deserialize, Serialize};
 use std::{
-    collections::HashMap,
-    fs::File,
+    collections::{HashMap, HashSet},
+    fs::{self, File},
     io::{self, Read},
     path::Path,
+    sync::LazyLock,
 };
 
-#[derive(Debug)]
+// Regex to remove Rust hash suffixes
+static HEX_SUFFIX_REGEX: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"::h[0-9a-f]+$").unwrap());
+
+#[derive(Debug, Clone, Default, PartialEq)]
 pub struct FunctionInfo {
     pub demangled_name: String,
     pub mangled_name: String,
+    /// Virtual address of the symbol (`st_value`).
+    pub address: u64,
+    /// File offset of the symbol, as expected by uprobe attachment.
     pub offset: u64,
+    /// Arguments to capture on entry.
+    pub arguments: Vec<ArgSpec>,
+    /// How to report the return value, if configured.
+    pub return_value: Option<ReturnSpec>,
+    /// Count calls in the kernel and report them as metrics only.
+    pub aggregate: bool,
+    /// Share of calls that produce spans; all calls when `None`.
+    pub sample_rate: Option<SampleRate>,
+    /// `service.name` configured for the binary or process the function was
+    /// found in.
+    pub service_name: Option<String>,
+}
+
+/// ELF symbol tables that can be searched for functions.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SymbolTable {
+    /// `.symtab`/`.strtab`, usually absent from stripped binaries.
+    Symtab,
+    /// `.dynsym`/`.dynstr`, the symbols exported by shared libraries.
+    Dynsym,
+}
+
+fn default_symbol_tables() -> Vec<SymbolTable> {
+    vec![SymbolTable::Symtab, SymbolTable::Dynsym]
+}
+
+fn default_max_matches_per_selector() -> usize {
+    1000
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct BinaryConfig {
+    #[serde(default)]
     pub path: String,
+    /// `service.name` of processes running this binary. Defaults to the
+    /// binary's file name.
+    #[serde(default)]
+    pub service_name: Option<String>,
+    /// Function selectors: exact demangled names, globs such as
+    /// `myapp::handlers::*`, `regex:<pattern>`, and `!`-prefixed exclusions.
     pub functions: Vec<String>,
+    /// Symbol tables to search, in order of preference.
+    #[serde(default = "default_symbol_tables")]
+    pub symbol_tables: Vec<SymbolTable>,
+    /// Upper bound on the functions a single selector may match.
+    #[serde(default = "default_max_matches_per_selector")]
+    pub max_matches_per_selector: usize,
+    /// Arguments to capture, keyed by demangled function name, e.g.
+    /// `["arg0: u64 as \"user_id\"", "arg1: str(64) as \"path\""]`.
+    #[serde(default)]
+    pub arguments: HashMap<String, Vec<String>>,
+    /// Return value type and error rule, keyed by demangled function name,
+    /// e.g. `{"type": "i32", "error_when": "negative"}`.
+    #[serde(default)]
+    pub return_values: HashMap<String, ReturnConfig>,
+    /// Hot functions, by demangled name, whose calls are aggregated in the
+    /// kernel and published as metrics instead of producing spans. Calls
+    /// nested in an aggregated call become children of its closest traced
+    /// caller; without one, they are root spans sharing a single trace.
+    #[serde(default)]
+    pub aggregate: Vec<String>,
+    /// Head sampling rates, keyed by demangled function name, e.g. `0.01` or
+    /// `{"one_in": 100}`. Unsampled calls still count towards call metrics.
+    #[serde(default)]
+    pub sampling: HashMap<String, SampleRate>,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+impl Default for BinaryConfig {
+    fn default() -> Self {
+        Self {
+            path: String::new(),
+            service_name: None,
+            functions: Vec::new(),
+            symbol_tables: default_symbol_tables(),
+            max_matches_per_selector: default_max_matches_per_selector(),
+            arguments: HashMap::new(),
+            return_values: HashMap::new(),
+            aggregate: Vec::new(),
+            sampling: HashMap::new(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
 pub struct InstrumentationConfig {
+    #[serde(default)]
     pub binaries: Vec<BinaryConfig>,
+    /// Running processes whose mapped executable and libraries are
+    /// instrumented.
+    #[serde(default)]
+    pub processes: Vec<ProcessConfig>,
+    #[serde(default)]
+    pub debug_info: DebugInfoConfig,
+    #[serde(default)]
+    pub exporter: ExporterConfig,
+    #[serde(default)]
+    pub events: EventsConfig,
+    #[serde(default)]
+    pub metrics: MetricsConfig,
+    #[serde(default)]
+    pub tail_sampling: TailSamplingConfig,
+    /// Processes to trace; all processes running the binaries by default.
+    #[serde(default)]
+    pub filter: FilterConfig,
+    /// Instrument processes matching `processes` that start later.
+    #[serde(default)]
+    pub watch: WatchConfig,
+}
+
+impl InstrumentationConfig {
+    pub fn from_file<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let file = File::open(path)?;
+        let config: Self = serde_json::from_reader(file)?;
+        println!("Parsed config: {:?}", config);
+        if config.binaries.iter().any(|binary| binary.path.is_empty()) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "binaries entries need a path",
+            ));
+        }
+        for process in &config.processes {
+            process.validate()?;
+        }
+        config.exporter.validate()?;
+        config.events.validate()?;
+        config.metrics.validate()?;
+        config.tail_sampling.validate()?;
+        config.filter.validate()?;
+        config.watch.validate()?;
+        Ok(config)
+    }
+}
+
+/// A defined function symbol read from one of the ELF symbol tables.
+#[derive(Debug, Clone)]
+pub struct ElfSymbol {
+    pub mangled_name: String,
+    /// Demangled name with the Rust hash suffix removed.
+    pub demangled_name: String,
+    pub address: u64,
 }
 
 #[derive(Debug, Default)]
 pub struct OffsetTracker {
     pub offsets: HashMap<String, HashMap<String, FunctionInfo>>, // {binary: {function: offset}}
+    /// Functions found in the objects mapped by configured processes, by pid.
+    pub processes: HashMap<u32, TrackedProcess>,
+}
+
+/// A running process selected by a `processes` entry.
+#[derive(Debug, Default)]
+pub struct TrackedProcess {
+    /// {object path: {function: offset}}, for objects with at least one
+    /// matching function.
+    pub objects: HashMap<String, HashMap<String, FunctionInfo>>,
 }
 
 impl OffsetTracker {
-    pub fn from_config_file<P: AsRef<Path>>(path: P) -> io::Result<Self> {
-        println!("offset_traker::from_config_file");
-        let file = File::open(path)?;
-        let config: InstrumentationConfig = serde_json::from_reader(file)?;
-        println!("Parsed config: {:?}", config);
+    pub fn from_config(config: &InstrumentationConfig) -> io::Result<Self> {
         let mut tracker = Self::default();
 
         for binary in &config.binaries {
-            println!("Processing binary: {}", binary.path);
-            let mut file = File::open(&binary.path)?;
-            let mut buffer = Vec::new();
-            file.read_to_end(&mut buffer)?;
-
-            let elf = Elf::parse(&buffer).map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
-            println!("Parsed ELF file:");
-            let hex_suffix_regex = Regex::new(r"::h[0-9a-f]+$").unwrap(); // Regex to remove hash suffixes
-
-            // Store function offsets after demangling Rust symbols
-            let function_offsets = elf
-                .syms
-                .iter()
-                .filter_map(|sym| {
-                    if let Some(mangled_name) = elf.strtab.get_at(sym.st_name) {
-                        let demangled = demangle(mangled_name).to_string(); // Demangle Rust symbol
-                        let cleaned_name = hex_suffix_regex.replace(&demangled, "").to_string(); // Remove hex suffix
-                        if binary.functions.contains(&cleaned_name) {
-                            println!(
-                                "✅ Matched function: {} at offset {:#x}",
-                                cleaned_name, sym.st_value
-                            );
-                            Some((
-                                cleaned_name.clone(),
-                                FunctionInfo {
-                                    demangled_name: cleaned_name,
-                                    mangled_name: mangled_name.to_string(),
-                                    offset: sym.st_value,
-                                },
-                            ))
-                        } else {
-                            None
-                        }
-                    } else {
-                        None
-                    }
-                })
-                .collect::<HashMap<String, FunctionInfo>>();
-
+            let function_offsets = Self::resolve_binary(binary, &config.debug_info)?;
             tracker
                 .offsets
                 .insert(binary.path.clone(), function_offsets);
         }
 
+        for process in &config.processes {
+            let pids = process.matching_pids()?;
+            if pids.is_empty() {
+                eprintln!("⚠️ No running process matches {:?}", process);
+            }
+            for pid in pids {
+                let tracked = Self::track_process(pid, process, &config.debug_info)?;
+                tracker.processes.insert(pid, tracked);
+            }
+        }
+
         Ok(tracker)
     }
+
+    /// Resolves the functions of a `processes` entry in process `pid`.
+    pub fn track_process(
+        pid: u32,
+        process: &ProcessConfig,
+        debug_info: &DebugInfoConfig,
+    ) -> io::Result<TrackedProcess> {
+        Ok(TrackedProcess {
+            objects: Self::resolve_process(pid, &process.binary, debug_info)?,
+        })
+    }
+
+    /// Resolves the configured functions in every object process `pid` has
+    /// mapped. Objects that cannot be read or parsed are skipped.
+    pub fn resolve_process(
+        pid: u32,
+        binary: &BinaryConfig,
+        debug_info: &DebugInfoConfig,
+    ) -> io::Result<HashMap<String, HashMap<String, FunctionInfo>>> {
+        let mut objects = HashMap::new();
+        for path in process::mapped_objects(pid)? {
+            let object = BinaryConfig {
+                path: path.to_string_lossy().into_owned(),
+                ..binary.clone()
+            };
+            match Self::resolve_binary(&object, debug_info) {
+                Ok(functions) if functions.is_empty() => {}
+                Ok(functions) => {
+                    objects.insert(object.path, functions);
+                }
+                Err(err) => eprintln!("⚠️ Skipping {} of pid {}: {}", object.path, pid, err),
+            }
+        }
+        Ok(objects)
+    }
+
+    /// Resolves the configured functions of one binary to their offsets.
+    pub fn resolve_binary(
+        binary: &BinaryConfig,
+        debug_info: &DebugInfoConfig,
+    ) -> io::Result<HashMap<String, FunctionInfo>> {
+        println!("Processing binary: {}", binary.path);
+        let mut file = File::open(&binary.path)?;
+        let mut buffer = Vec::new();
+        file.read_to_end(&mut buffer)?;
+
+        let elf = Elf::parse(&buffer).map_err(io::Error::other)?;
+        println!("Parsed ELF file:");
+
+        let selectors = FunctionSelectors::parse(&binary.functions)?;
+        let arguments = binary
+            .arguments
+            .iter()
+            .map(|(function, specs)| Ok((function.as_str(), ArgSpec::parse_all(specs)?)))
+            .collect::<io::Result<HashMap<&str, Vec<ArgSpec>>>>()?;
+        let return_values = binary
+            .return_values
+            .iter()
+            .map(|(function, config)| Ok((function.as_str(), ReturnSpec::from_config(config)?)))
+            .collect::<io::Result<HashMap<&str, ReturnSpec>>>()?;
+        for rate in binary.sampling.values() {
+            rate.validate()?;
+        }
+
+        // Stripped binaries keep their .symtab in a separate debug file. Its
+        // addresses match the original, so offsets still come from `elf`.
+        let mut debug_buffer = Vec::new();
+        if binary.symbol_tables.contains(&SymbolTable::Symtab) && elf.syms.is_empty() {
+            if let Some(debug_path) =
+                debuginfo::find_debug_file(Path::new(&binary.path), &elf, &buffer, debug_info)
+            {
+                println!(
+                    "Using debug file {} for {}",
+                    debug_path.display(),
+                    binary.path
+                );
+                debug_buffer = fs::read(&debug_path)?;
+            }
+        }
+        let debug_elf = if debug_buffer.is_empty() {
+            None
+        } else {
+            Some(Elf::parse(&debug_buffer).map_err(io::Error::other)?)
+        };
+
+        let symbols = collect_symbols(&elf, &binary.symbol_tables, debug_elf.as_ref());
+
+        // Store function offsets after demangling Rust symbols
+        let function_offsets = selectors
+            .select(&symbols, binary.max_matches_per_selector)?
+            .into_iter()
+            .filter_map(|sym| {
+                let Some(offset) = vaddr_to_file_offset(&elf.program_headers, sym.address) else {
+                    eprintln!(
+                        "⚠️ {} at {:#x} is not in a loadable segment, skipping",
+                        sym.demangled_name, sym.address
+                    );
+                    return None;
+                };
+                println!(
+                    "✅ Matched function: {} at address {:#x}, file offset {:#x}",
+                    sym.demangled_name, sym.address, offset
+                );
+                Some((
+                    sym.demangled_name.clone(),
+                    FunctionInfo {
+                        demangled_name: sym.demangled_name.clone(),
+                        mangled_name: sym.mangled_name.clone(),
+                        address: sym.address,
+                        offset,
+                        arguments: arguments
+                            .get(sym.demangled_name.as_str())
+                            .cloned()
+                            .unwrap_or_default(),
+                        return_value: return_values.get(sym.demangled_name.as_str()).copied(),
+                        aggregate: binary.aggregate.contains(&sym.demangled_name),
+                        sample_rate: binary.sampling.get(&sym.demangled_name).copied(),
+                        service_name: binary.service_name.clone(),
+                    },
+                ))
+            })
+            .collect::<HashMap<String, FunctionInfo>>();
+
+        Ok(function_offsets)
+    }
+
     // Helper method to get mangled name for a function
+    #[allow(dead_code)] // Lookup API; the agent itself iterates `offsets`
     pub fn get_mangled_name(&self, binary_path: &str, demangled_name: &str) -> Option<&str> {
         self.offsets
             .get(binary_path)
@@ -96,6 +353,7 @@ impl OffsetTracker {
     }
 
     // Helper method to get offset for a function
+    #[allow(dead_code)] // Lookup API; the agent itself iterates `offsets`
     pub fn get_offset(&self, binary_path: &str, demangled_name: &str) -> Option<u64> {
         self.offsets
             .get(binary_path)
@@ -104,6 +362,65 @@ impl OffsetTracker {
     }
 }
 
+/// Reads the defined function symbols from the requested tables. A symbol
+/// present in several tables (e.g. exported functions of an unstripped shared
+/// library) is reported once, from the first table that lists it. When
+/// `debug_elf` is given, `.symtab` is read from it instead of `elf`.
+pub fn collect_symbols(
+    elf: &Elf,
+    tables: &[SymbolTable],
+    debug_elf: Option<&Elf>,
+) -> Vec<ElfSymbol> {
+    let mut seen = HashSet::new();
+    let mut symbols = Vec::new();
+
+    for table in tables {
+        let (syms, strtab) = match table {
+            SymbolTable::Symtab => {
+                let symtab_elf = debug_elf.unwrap_or(elf);
+                (&symtab_elf.syms, &symtab_elf.strtab)
+            }
+            SymbolTable::Dynsym => (&elf.dynsyms, &elf.dynstrtab),
+        };
+
+        for sym in syms.iter() {
+            // Skip imports (undefined) and non-function symbols
+            if sym.st_type() != STT_FUNC || sym.st_shndx == SHN_UNDEF as usize || sym.st_value == 0
+            {
+                continue;
+            }
+            let Some(mangled_name) = strtab.get_at(sym.st_name) else {
+                continue;
+            };
+            // Already reported from a preferred table
+            if !seen.insert(mangled_name) {
+                continue;
+            }
+
+            let demangled = demangle(mangled_name).to_string(); // Demangle Rust symbol
+            let demangled_name = HEX_SUFFIX_REGEX.replace(&demangled, "").to_string(); // Remove hex suffix
+            symbols.push(ElfSymbol {
+                mangled_name: mangled_name.to_string(),
+                demangled_name,
+                address: sym.st_value,
+            });
+        }
+    }
+
+    symbols
+}
+
+/// Translates a virtual address to a file offset using the PT_LOAD segment
+/// that contains it. Segments are not required to be loaded at an address
+/// equal to their file offset, so `st_value` cannot be used directly.
+pub fn vaddr_to_file_offset(program_headers: &[ProgramHeader], vaddr: u64) -> Option<u64> {
+    program_headers
+        .iter()
+        .filter(|ph| ph.p_type == PT_LOAD)
+        .find(|ph| vaddr >= ph.p_vaddr && vaddr < ph.p_vaddr + ph.p_filesz)
+        .map(|ph| vaddr - ph.p_vaddr + ph.p_offset)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -113,11 +430,12 @@ mod tests {
     use std::path::{Path, PathBuf};
     use std::process::Command;
 
-    /// Creates a small test ELF binary with a known function
-    fn create_test_binary() -> Result<PathBuf> {
+    /// Creates a small test ELF binary with a known function. `name` keeps
+    /// the files of concurrently running tests apart.
+    fn create_test_binary(name: &str) -> Result<PathBuf> {
         let temp_dir = env::temp_dir();
-        let c_file = temp_dir.join("test_program.c");
-        let bin_file = temp_dir.join("test_program");
+        let c_file = temp_dir.join(format!("{}.c", name));
+        let bin_file = temp_dir.join(name);
 
         let source_code = r#"
             #include <stdio.h>
@@ -154,18 +472,19 @@ mod tests {
 
     #[test]
     fn test_function_offset_extraction() -> Result<()> {
-        let test_binary = create_test_binary()?;
-        let mut tracker = OffsetTracker::default();
+        let test_binary = create_test_binary("test_program_offsets")?;
 
         let mut function_offsets = HashMap::new();
         function_offsets.insert("test_function".to_string(), 0);
         function_offsets.insert("another_function".to_string(), 0);
 
-        let mut config = InstrumentationConfig {
+        let config = InstrumentationConfig {
             binaries: vec![BinaryConfig {
                 path: test_binary.to_str().unwrap().to_string(),
                 functions: function_offsets.keys().cloned().collect(),
+                ..Default::default()
             }],
+            ..Default::default()
         };
 
         // Save config to a temporary file
@@ -174,19 +493,20 @@ mod tests {
         serde_json::to_writer_pretty(config_file, &config)?;
 
         // Load offsets from the binary
-        tracker.from_config_file(&config_path)?;
+        let tracker = OffsetTracker::from_config(&InstrumentationConfig::from_file(&config_path)?)?;
 
         for func in function_offsets.keys() {
-            let offset = tracker
-                .offsets
-                .get(test_binary.to_str().unwrap())
-                .and_then(|f| f.get(func));
+            let offset = tracker.get_offset(test_binary.to_str().unwrap(), func);
 
             assert!(
                 offset.is_some(),
                 "Function '{}' should have an offset",
                 func
             );
+            assert_eq!(
+                tracker.get_mangled_name(test_binary.to_str().unwrap(), func),
+                Some(func.as_str())
+            );
             println!(
                 "✅ Found function '{}' at offset: {:#x}",
                 func,
@@ -203,14 +523,15 @@ mod tests {
 
     #[test]
     fn test_missing_function() -> Result<()> {
-        let test_binary = create_test_binary()?;
-        let mut tracker = OffsetTracker::default();
+        let test_binary = create_test_binary("test_program_missing")?;
 
-        let mut config = InstrumentationConfig {
+        let config = InstrumentationConfig {
             binaries: vec![BinaryConfig {
                 path: test_binary.to_str().unwrap().to_string(),
                 functions: vec!["non_existent_function".to_string()],
+                ..Default::default()
             }],
+            ..Default::default()
         };
 
         // Save config to a temporary file
@@ -218,12 +539,9 @@ mod tests {
         let config_file = File::create(&config_path)?;
         serde_json::to_writer_pretty(config_file, &config)?;
 
-        tracker.from_config_file(&config_path)?;
+        let tracker = OffsetTracker::from_config(&InstrumentationConfig::from_file(&config_path)?)?;
 
-        let offset = tracker
-            .offsets
-            .get(test_binary.to_str().unwrap())
-            .and_then(|f| f.get("non_existent_function"));
+        let offset = tracker.get_offset(test_binary.to_str().unwrap(), "non_existent_function");
 
         assert!(offset.is_none(), "Non-existent function should return None");
 
@@ -233,4 +551,182 @@ mod tests {
 
         Ok(())
     }
+
+    #[test]
+    fn test_vaddr_to_file_offset_uses_containing_load_segment() {
+        let segment = |p_type, p_offset, p_vaddr, p_filesz| ProgramHeader {
+            p_type,
+            p_offset,
+            p_vaddr,
+            p_filesz,
+            p_memsz: p_filesz,
+            ..Default::default()
+        };
+        let program_headers = vec![
+            segment(goblin::elf::program_header::PT_PHDR, 0x40, 0x400040, 0x2d8),
+            segment(PT_LOAD, 0x0, 0x400000, 0x600),
+            segment(PT_LOAD, 0x1000, 0x601000, 0x2000),
+        ];
+
+        assert_eq!(
+            vaddr_to_file_offset(&program_headers, 0x400100),
+            Some(0x100)
+        );
+        assert_eq!(
+            vaddr_to_file_offset(&program_headers, 0x601234),
+            Some(0x1234)
+        );
+        assert_eq!(vaddr_to_file_offset(&program_headers, 0x500000), None);
+    }
+
+    #[test]
+    fn test_dynsym_lookup_in_stripped_shared_library() -> Result<()> {
+        let temp_dir = env::temp_dir();
+        let c_file = temp_dir.join("test_dynsym_lib.c");
+        let lib_file = temp_dir.join("libtest_dynsym.so");
+        File::create(&c_file)?.write_all(
+            b"int exported_function(int x) { return x * 2; }\n\
+              static int internal_function(int x) { return x + 1; }\n\
+              int use_internal(int x) { return internal_function(x); }\n",
+        )?;
+
+        let output = Command::new("gcc")
+            .args(["-shared", "-fPIC", "-O0", "-o"])
+            .arg(&lib_file)
+            .arg(&c_file)
+            .output()?;
+        assert!(
+            output.status.success(),
+            "Compilation failed: {}",
+            String::from_utf8_lossy(&output.stderr)
+        );
+        let output = Command::new("strip").arg(&lib_file).output()?;
+        assert!(output.status.success(), "strip failed");
+
+        let functions = vec!["exported_function".to_string()];
+        let symtab_only = BinaryConfig {
+            path: lib_file.to_str().unwrap().to_string(),
+            functions: functions.clone(),
+            symbol_tables: vec![SymbolTable::Symtab],
+            ..Default::default()
+        };
+        assert!(
+            OffsetTracker::resolve_binary(&symtab_only, &DebugInfoConfig::default())?.is_empty()
+        );
+
+        let with_dynsym = BinaryConfig {
+            path: lib_file.to_str().unwrap().to_string(),
+            functions,
+            ..Default::default()
+        };
+        let resolved = OffsetTracker::resolve_binary(&with_dynsym, &DebugInfoConfig::default())?;
+        let function = resolved
+            .get("exported_function")
+            .expect("exported_function should be found in .dynsym");
+        assert!(function.offset > 0);
+
+        let _ = fs::remove_file(&c_file);
+        let _ = fs::remove_file(&lib_file);
+        Ok(())
+    }
+
+    /// Splits `binary` into a stripped binary and `<binary>.debug`, the way
+    /// distribution debug packages are built.
+    fn split_debug_info(binary: &Path) -> Result<PathBuf> {
+        let debug_file = binary.with_extension("debug");
+        let steps: [&[&std::ffi::OsStr]; 2] = [
+            &[
+                "--only-keep-debug".as_ref(),
+                binary.as_os_str(),
+                debug_file.as_os_str(),
+            ],
+            &["--strip-all".as_ref(), binary.as_os_str()],
+        ];
+        for args in steps {
+            let output = Command::new("objcopy").args(args).output()?;
+            assert!(
+                output.status.success(),
+                "objcopy failed: {}",
+                String::from_utf8_lossy(&output.stderr)
+            );
+        }
+        Ok(debug_file)
+    }
+
+    fn stripped_binary_config(binary: &Path) -> BinaryConfig {
+        BinaryConfig {
+            path: binary.to_str().unwrap().to_string(),
+            functions: vec!["test_function".to_string()],
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_symbols_from_gnu_debuglink() -> Result<()> {
+        let test_binary = create_test_binary("test_program_debuglink")?;
+        let unstripped = OffsetTracker::resolve_binary(
+            &stripped_binary_config(&test_binary),
+            &DebugInfoConfig::default(),
+        )?;
+        let debug_file = split_debug_info(&test_binary)?;
+
+        let no_debug_dirs = DebugInfoConfig {
+            debug_dirs: vec![],
+            ..Default::default()
+        };
+        let config = stripped_binary_config(&test_binary);
+        assert!(OffsetTracker::resolve_binary(&config, &no_debug_dirs)?.is_empty());
+
+        let output = Command::new("objcopy")
+            .arg(format!("--add-gnu-debuglink={}", debug_file.display()))
+            .arg(&test_binary)
+            .output()?;
+        assert!(
+            output.status.success(),
+            "objcopy --add-gnu-debuglink failed"
+        );
+
+        let resolved = OffsetTracker::resolve_binary(&config, &no_debug_dirs)?;
+        assert_eq!(
+            resolved["test_function"].offset,
+            unstripped["test_function"].offset
+        );
+
+        // A debug file that no longer matches the CRC must be ignored
+        fs::OpenOptions::new()
+            .append(true)
+            .open(&debug_file)?
+            .write_all(b"tampered")?;
+        assert!(OffsetTracker::resolve_binary(&config, &no_debug_dirs)?.is_empty());
+
+        cleanup_test_files(&test_binary);
+        let _ = fs::remove_file(&debug_file);
+        Ok(())
+    }
+
+    #[test]
+    fn test_symbols_from_build_id_debug_dir() -> Result<()> {
+        let test_binary = create_test_binary("test_program_build_id")?;
+        let buffer = fs::read(&test_binary)?;
+        let build_id = debuginfo::build_id(&Elf::parse(&buffer).unwrap(), &buffer)
+            .expect("gcc should emit a build-id note");
+        let debug_file = split_debug_info(&test_binary)?;
+
+        let debug_dir = env::temp_dir().join("test_program_build_id_debug");
+        let build_id_path = debuginfo::build_id_path(&debug_dir, &build_id).unwrap();
+        fs::create_dir_all(build_id_path.parent().unwrap())?;
+        fs::rename(&debug_file, &build_id_path)?;
+
+        let debug_info = DebugInfoConfig {
+            debug_dirs: vec![debug_dir.to_str().unwrap().to_string()],
+            ..Default::default()
+        };
+        let resolved =
+            OffsetTracker::resolve_binary(&stripped_binary_config(&test_binary), &debug_info)?;
+        assert!(resolved.contains_key("test_function"));
+
+        cleanup_test_files(&test_binary);
+        let _ = fs::remove_dir_all(&debug_dir);
+        Ok(())
+    }
 }
@@ -1,118 +1,281 @@
+use crate::capture::{ARG_DATA_SIZE, MAX_ARGS};
 use anyhow::Result;
+use libbpf_rs::Link;
 use libbpf_rs::MapCore;
-//use libbpf_rs::{ObjectBuilder, UprobeAttachType};
+use libbpf_rs::MapFlags;
+use libbpf_rs::MapHandle;
+use libbpf_rs::Object;
 use libbpf_rs::ObjectBuilder;
 use libbpf_rs::RingBufferBuilder;
 use libbpf_rs::UprobeOpts;
 use serde::{Deserialize, Serialize};
-use std::env;
-use std::path::PathBuf;
+use std::io;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
 use tokio::sync::mpsc::Sender;
-use tokio::sync::Mutex;
 
+/// The compiled BPF object, embedded so the agent does not depend on the
+/// build directory at run time.
+const BPF_OBJECT: &[u8] = include_bytes!(env!("BPF_OBJECT"));
+
+fn default_ring_buffer_size_mb() -> u32 {
+    16
+}
+
+/// Sizing of the ring buffer all probes submit their events to. Events that
+/// do not fit are dropped in the kernel and counted.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct EventsConfig {
+    /// Ring buffer size in MiB, a power of two.
+    #[serde(default = "default_ring_buffer_size_mb")]
+    pub ring_buffer_size_mb: u32,
+}
+
+impl Default for EventsConfig {
+    fn default() -> Self {
+        Self {
+            ring_buffer_size_mb: default_ring_buffer_size_mb(),
+        }
+    }
+}
+
+impl EventsConfig {
+    pub fn validate(&self) -> io::Result<()> {
+        if !self.ring_buffer_size_mb.is_power_of_two() || self.ring_buffer_size_mb > 1024 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "events ring_buffer_size_mb must be a power of two up to 1024, got {}",
+                    self.ring_buffer_size_mb
+                ),
+            ));
+        }
+        Ok(())
+    }
+
+    /// Size in bytes, as the ring buffer's `max_entries`.
+    pub fn ring_buffer_size(&self) -> u32 {
+        self.ring_buffer_size_mb << 20
+    }
+}
+
+/// A uprobe/uretprobe pair attached to one function. The BPF object and ring
+/// buffer are shared by all probes; each probe only owns its attach links,
+/// which stay attached until `detach()` is called or the probe is dropped.
 pub struct Probe {
-    pub(crate) bpf_object: Arc<Mutex<libbpf_rs::Object>>,
-    event_channel: Sender<BPFEvent>,
+    pub binary_path: String,
+    pub function_name: String,
+    /// Passed to both programs as the BPF cookie and echoed back in `BPFEvent`.
+    pub function_id: u64,
+    /// Process the probe is restricted to, if any.
+    pub pid: Option<u32>,
+    entry_link: Option<Link>,
+    ret_link: Option<Link>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProbeStatus {
+    Attached,
+    Detached,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+/// Offset of `args` in `struct event_t`.
+const EVENT_ARGS_OFFSET: usize = 104;
+/// Size of `struct event_t`.
+const EVENT_SIZE: usize = EVENT_ARGS_OFFSET + MAX_ARGS * ARG_DATA_SIZE;
+
+#[derive(Debug)]
 #[repr(C)] // Ensure correct memory layout
 pub struct BPFEvent {
     pub timestamp_start: u64,
     pub timestamp_end: u64,
+    pub function_id: u64,
+    /// Raw return register, interpreted with the function's `ReturnSpec`.
+    pub retval: u64,
+    /// Shared by every call nested under the same outermost traced call.
+    pub trace_id: u128,
+    pub span_id: u64,
+    /// Span of the enclosing traced call on the same thread, 0 for a root.
+    pub parent_span_id: u64,
     pub pid: u32,
+    pub tid: u32,
+    /// CPU the call returned on.
+    pub cpu: u32,
+    /// Bit `i` is set when argument `i` could not be read.
+    pub arg_errors: u32,
+    /// cgroup v2 id of the calling task.
+    pub cgroup_id: u64,
     pub comm: [u8; 16], // Fixed-size array to match `char comm[16]` in C
+    /// Raw captured arguments, decoded with the function's `ArgSpec`s.
+    pub args: [[u8; ARG_DATA_SIZE]; MAX_ARGS],
 }
 
 impl BPFEvent {
     pub fn parse(data: &[u8]) -> anyhow::Result<Self> {
-        if data.len() < 28 {
+        if data.len() < EVENT_SIZE {
             return Err(anyhow::anyhow!("Invalid event data size"));
         }
 
         let timestamp_start = u64::from_ne_bytes(data[0..8].try_into()?);
         let timestamp_end = u64::from_ne_bytes(data[8..16].try_into()?);
-        let pid = u32::from_ne_bytes(data[16..20].try_into()?);
+        let function_id = u64::from_ne_bytes(data[16..24].try_into()?);
+        let retval = u64::from_ne_bytes(data[24..32].try_into()?);
+        let trace_id_high = u64::from_ne_bytes(data[32..40].try_into()?);
+        let trace_id_low = u64::from_ne_bytes(data[40..48].try_into()?);
+        let span_id = u64::from_ne_bytes(data[48..56].try_into()?);
+        let parent_span_id = u64::from_ne_bytes(data[56..64].try_into()?);
+        let pid = u32::from_ne_bytes(data[64..68].try_into()?);
+        let tid = u32::from_ne_bytes(data[68..72].try_into()?);
+        let cpu = u32::from_ne_bytes(data[72..76].try_into()?);
+        let arg_errors = u32::from_ne_bytes(data[76..80].try_into()?);
+        let cgroup_id = u64::from_ne_bytes(data[80..88].try_into()?);
 
         let mut comm = [0u8; 16]; // Fixed-size array
-        comm.copy_from_slice(&data[20..36]); // Copy only 16 bytes
+        comm.copy_from_slice(&data[88..104]); // Copy only 16 bytes
+
+        let mut args = [[0u8; ARG_DATA_SIZE]; MAX_ARGS];
+        for (i, arg) in args.iter_mut().enumerate() {
+            let start = EVENT_ARGS_OFFSET + i * ARG_DATA_SIZE;
+            arg.copy_from_slice(&data[start..start + ARG_DATA_SIZE]);
+        }
 
         Ok(Self {
             timestamp_start,
             timestamp_end,
+            function_id,
+            retval,
+            trace_id: (trace_id_high as u128) << 64 | trace_id_low as u128,
+            span_id,
+            parent_span_id,
             pid,
+            tid,
+            cpu,
+            arg_errors,
+            cgroup_id,
             comm,
+            args,
         })
     }
+
+    /// Command name of the calling task, without the trailing NUL padding.
+    pub fn comm(&self) -> String {
+        let len = self
+            .comm
+            .iter()
+            .position(|&b| b == 0)
+            .unwrap_or(self.comm.len());
+        String::from_utf8_lossy(&self.comm[..len]).into_owned()
+    }
+
+    /// Wall time spent in the call, as measured by the kernel.
+    pub fn duration(&self) -> std::time::Duration {
+        std::time::Duration::from_nanos(self.timestamp_end.saturating_sub(self.timestamp_start))
+    }
+}
+
+/// Opens and loads the BPF object shared by every probe, with an `events`
+/// ring buffer of `ring_buffer_size` bytes.
+pub fn load_bpf_object(ring_buffer_size: u32) -> Result<Object> {
+    let mut open_obj = ObjectBuilder::default().open_memory(BPF_OBJECT)?;
+    let mut events = open_obj
+        .maps_mut()
+        .find(|map| map.name() == "events")
+        .ok_or_else(|| anyhow::anyhow!("events map not found"))?;
+    events.set_max_entries(ring_buffer_size)?;
+    Ok(open_obj.load()?)
+}
+
+/// Consumes the shared `events` ring buffer and forwards parsed events until
+/// `stop` is set, polling fails or the receiving side goes away. Blocks the
+/// calling thread; `stop` is checked at least every 100ms.
+pub fn poll_events(
+    events_map: MapHandle,
+    event_channel: Sender<BPFEvent>,
+    stop: Arc<AtomicBool>,
+) -> Result<()> {
+    let mut ringbuf_builder = RingBufferBuilder::new();
+    ringbuf_builder.add(&events_map as &dyn MapCore, |data: &[u8]| {
+        match BPFEvent::parse(data) {
+            Ok(event) => {
+                if let Err(err) = event_channel.blocking_send(event) {
+                    eprintln!("Failed to send event: {}", err);
+                    return -1;
+                }
+            }
+            Err(err) => eprintln!("Failed to parse BPF event: {}", err),
+        }
+        0
+    })?;
+
+    let ringbuf = ringbuf_builder.build()?;
+
+    while !stop.load(Ordering::Relaxed) {
+        ringbuf.poll(std::time::Duration::from_millis(100))?;
+    }
+    Ok(())
+}
+
+/// Total of the per-CPU counts in the `dropped_events` map.
+pub fn dropped_events(dropped_events_map: &MapHandle) -> Result<u64> {
+    let values = dropped_events_map
+        .lookup_percpu(&0u32.to_ne_bytes(), MapFlags::ANY)?
+        .unwrap_or_default();
+    sum_per_cpu(&values)
+}
+
+fn sum_per_cpu(values: &[Vec<u8>]) -> Result<u64> {
+    values.iter().try_fold(0u64, |total, value| {
+        let count = u64::from_ne_bytes(
+            value
+                .get(..8)
+                .and_then(|bytes| bytes.try_into().ok())
+                .ok_or_else(|| anyhow::anyhow!("Invalid per-CPU counter size"))?,
+        );
+        Ok(total + count)
+    })
 }
 
 impl Probe {
+    /// Attaches to calls made by process `pid`, or by every process.
     pub fn new(
+        bpf_object: &Object,
+        pid: Option<u32>,
         binary_path: &str,
         function_name: &str,
-        event_channel: Sender<BPFEvent>,
         function_offset: u64,
+        function_id: u64,
     ) -> Result<Self> {
-        let bpf_path = "target/debug/probe.bpf.o";
-        println!("Loading eBPF program from: {:?}", bpf_path);
-
-        let mut bpf_object = ObjectBuilder::default().open_file(bpf_path)?;
-        println!("BPF object before loading - programs:");
-        for prog in bpf_object.progs() {
-            println!("Pre-load prog: {:?} Type: {:?}", prog.name(), prog.prog_type());
-        }
-        
-        let open_obj = bpf_object.load()?;
-        println!("BPF object after loading - programs:");
-        for prog in open_obj.progs() {
-            println!("Post-load prog: {:?} Type: {:?}", prog.name(), prog.prog_type());
-        }
-
-        println!("Loaded eBPF program for probe: {}", function_name);
-
-        println!("Available eBPF programs:");
-        for prog in open_obj.progs() {
-            println!("- {:?}", prog.name());
-        }
-
-        //let program = open_obj
-        //    .prog("uprobe_handler")
-        //    .ok_or_else(|| anyhow::anyhow!("Failed to find uprobe handler"))?;
-        let entry_program = open_obj
+        let entry_program = bpf_object
             .progs_mut()
             .find(|p| p.name() == "trace_enter")
             .ok_or_else(|| anyhow::anyhow!("Failed to find entry probe"))?;
+        // `func_name` is left unset: `function_offset` is already an absolute
+        // file offset, and libbpf would otherwise add it to the symbol's own.
         let entry_opts = UprobeOpts {
             retprobe: false,
-            func_name: function_name.to_string(),            
+            cookie: function_id,
             ..Default::default()
         };
         println!(
             "Attaching uprobe for function: {:?} at offset {:#x}",
             function_name, function_offset
         );
-        let _entry_link = entry_program.attach_uprobe_with_opts(
-            -1,
+        let entry_link = entry_program.attach_uprobe_with_opts(
+            pid.map_or(-1, |pid| pid as i32),
             binary_path,
             function_offset as usize,
             entry_opts,
         )?;
 
-        println!(
-            "✅ Attached eBPF probe for '{:?}' at offset: {:#x}",
-            function_name, function_offset
-        );
-
         // Attach return probe
-        let ret_program = open_obj
+        let ret_program = bpf_object
             .progs_mut()
             .find(|p| p.name() == "trace_exit")
             .ok_or_else(|| anyhow::anyhow!("Failed to find return probe"))?;
 
         let ret_opts = UprobeOpts {
             retprobe: true, // Return probe
-            func_name: function_name.to_string(),
+            cookie: function_id,
             ..Default::default()
         };
 
@@ -121,64 +284,123 @@ impl Probe {
             function_name, function_offset
         );
 
-        let _ret_link = ret_program.attach_uprobe_with_opts(
-            -1,
+        let ret_link = ret_program.attach_uprobe_with_opts(
+            pid.map_or(-1, |pid| pid as i32),
             binary_path,
             function_offset as usize,
             ret_opts,
         )?;
 
         println!(
-            "✅ Attached eBPF probes for '{}' at offset: {:#x}",
-            function_name, function_offset
+            "✅ Attached eBPF probes for '{}' at offset: {:#x} (id {})",
+            function_name, function_offset, function_id
         );
-        let bpf_object = Arc::new(Mutex::new(open_obj));
 
         Ok(Self {
-            bpf_object,
-            event_channel,
+            binary_path: binary_path.to_string(),
+            function_name: function_name.to_string(),
+            function_id,
+            pid,
+            entry_link: Some(entry_link),
+            ret_link: Some(ret_link),
         })
     }
 
-    pub async fn run(&self) -> Result<()> {
-        println!("-----> Running probe...");
-        let mut ringbuf_builder = RingBufferBuilder::new();
-        println!("---> Got ringbuf builder: {:?}", ringbuf_builder);
-
-        // Try locking bpf_object once to configure the ring buffer
-        let mut bpf_object = self.bpf_object.lock().await;
-        println!("probe_run Got bpf_object lock: {:?}", bpf_object);
-
-        let events_map = bpf_object
-            .maps()
-            .find(|m| m.name().to_string_lossy().as_ref() == "events")
-            .expect("events map not found");
-        println!("---> Found events map: {:?}", events_map.name());
-        ringbuf_builder.add(&events_map as &dyn MapCore, |data: &[u8]| {
-            println!("Received data: {:?}", data);
-            match BPFEvent::parse(data) {
-                Ok(event) => {
-                    println!("====> GOT Event {:?}", event);
-                    if let Err(err) = self.event_channel.blocking_send(event) {
-                        eprintln!("Failed to send event: {}", err);
-                    }
-                }
-                Err(err) => eprintln!("Failed to parse BPF event: {}", err),
-            }
-            0
-        })?;
+    pub fn status(&self) -> ProbeStatus {
+        if self.entry_link.is_some() && self.ret_link.is_some() {
+            ProbeStatus::Attached
+        } else {
+            ProbeStatus::Detached
+        }
+    }
 
-        let ringbuf = ringbuf_builder.build()?;
-        drop(bpf_object);
+    pub fn is_attached(&self) -> bool {
+        self.status() == ProbeStatus::Attached
+    }
+
+    /// Detaches both the entry and return probe. Detaching an already
+    /// detached probe is a no-op.
+    pub fn detach(&mut self) -> Result<()> {
+        if self.entry_link.is_none() && self.ret_link.is_none() {
+            return Ok(());
+        }
 
-        println!("---> Polling ring buffer...");
+        // Detach the entry probe first so no new calls are recorded while
+        // in-flight ones can still complete through the return probe.
+        let entry_result = self.entry_link.take().map_or(Ok(()), |link| link.detach());
+        let ret_result = self.ret_link.take().map_or(Ok(()), |link| link.detach());
+        entry_result?;
+        ret_result?;
+
+        println!(
+            "Detached eBPF probes for '{}' in {} (id {})",
+            self.function_name, self.binary_path, self.function_id
+        );
+        Ok(())
+    }
+}
 
-        loop {
-            ringbuf.poll(std::time::Duration::from_millis(100))?;
+impl Drop for Probe {
+    fn drop(&mut self) {
+        if let Err(err) = self.detach() {
+            eprintln!(
+                "Failed to detach probe for '{}' in {}: {}",
+                self.function_name, self.binary_path, err
+            );
         }
     }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_event_layout() {
+        let mut data = vec![0u8; EVENT_SIZE];
+        data[0..8].copy_from_slice(&100u64.to_ne_bytes());
+        data[8..16].copy_from_slice(&350u64.to_ne_bytes());
+        data[16..24].copy_from_slice(&7u64.to_ne_bytes());
+        data[24..32].copy_from_slice(&(-1i64).to_ne_bytes());
+        data[32..40].copy_from_slice(&0x0102u64.to_ne_bytes());
+        data[40..48].copy_from_slice(&0x0304u64.to_ne_bytes());
+        data[48..56].copy_from_slice(&0xaau64.to_ne_bytes());
+        data[56..64].copy_from_slice(&0xbbu64.to_ne_bytes());
+        data[64..68].copy_from_slice(&4242u32.to_ne_bytes());
+        data[68..72].copy_from_slice(&4243u32.to_ne_bytes());
+        data[72..76].copy_from_slice(&3u32.to_ne_bytes());
+        data[76..80].copy_from_slice(&0b10u32.to_ne_bytes());
+        data[80..88].copy_from_slice(&0x1234u64.to_ne_bytes());
+        data[88..94].copy_from_slice(b"server");
+        data[EVENT_ARGS_OFFSET + ARG_DATA_SIZE] = 0x5a;
+
+        let event = BPFEvent::parse(&data).unwrap();
+        assert_eq!(event.duration().as_nanos(), 250);
+        assert_eq!(event.function_id, 7);
+        assert_eq!(event.retval as i64, -1);
+        assert_eq!(event.trace_id, 0x0102 << 64 | 0x0304);
+        assert_eq!((event.span_id, event.parent_span_id), (0xaa, 0xbb));
+        assert_eq!((event.pid, event.tid, event.cpu), (4242, 4243, 3));
+        assert_eq!((event.arg_errors, event.cgroup_id), (0b10, 0x1234));
+        assert_eq!(event.comm(), "server");
+        assert_eq!(event.args[1][0], 0x5a);
 
-    fn process_event(&self, data: &[u8]) -> anyhow::Result<BPFEvent> {
-        BPFEvent::parse(data)
+        assert!(BPFEvent::parse(&data[..EVENT_SIZE - 1]).is_err());
+    }
+
+    #[test]
+    fn test_ring_buffer_size() {
+        assert_eq!(EventsConfig::default().ring_buffer_size(), 16 << 20);
+        assert_eq!(sum_per_cpu(&[]).unwrap(), 0);
+        assert_eq!(
+            sum_per_cpu(&[3u64.to_ne_bytes().to_vec(), 4u64.to_ne_bytes().to_vec()]).unwrap(),
+            7
+        );
+        for size in [0, 3, 2048] {
+            let config = EventsConfig {
+                ring_buffer_size_mb: size,
+            };
+            assert!(config.validate().is_err(), "{}", size);
+        }
     }
 }
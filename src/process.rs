@@ -0,0 +1,205 @@
+use crate::offset_tracker::BinaryConfig;
+use proc_maps::MapRange;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// Appended by the kernel to mappings whose file was replaced or removed.
+const DELETED_SUFFIX: &str = " (deleted)";
+
+/// Instruments running processes instead of a binary path: every ELF object
+/// a matching process has mapped (its executable and loaded libraries) is
+/// searched for the configured functions. Exactly one of `pid`, `comm` and
+/// `exe` must be set.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ProcessConfig {
+    #[serde(default)]
+    pub pid: Option<u32>,
+    /// Regex matched against the command name in `/proc/<pid>/comm`.
+    #[serde(default)]
+    pub comm: Option<String>,
+    /// Regex matched against the executable path, as seen by the process.
+    #[serde(default)]
+    pub exe: Option<String>,
+    /// Functions and capture settings, as for a binary. `path` is not used.
+    #[serde(flatten)]
+    pub binary: BinaryConfig,
+}
+
+impl ProcessConfig {
+    pub fn validate(&self) -> io::Result<()> {
+        let selectors = [self.pid.is_some(), self.comm.is_some(), self.exe.is_some()];
+        if selectors.iter().filter(|&&set| set).count() != 1 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "processes entries need exactly one of pid, comm and exe",
+            ));
+        }
+        self.patterns().map(|_| ())
+    }
+
+    /// Pids of the running processes this entry selects, except the agent's.
+    pub fn matching_pids(&self) -> io::Result<Vec<u32>> {
+        if let Some(pid) = self.pid {
+            return Ok(vec![pid]);
+        }
+
+        let (comm, exe) = self.patterns()?;
+        let mut pids = Vec::new();
+        for entry in fs::read_dir("/proc")? {
+            let Ok(pid) = entry?.file_name().to_string_lossy().parse::<u32>() else {
+                continue;
+            };
+            if pid == std::process::id() {
+                continue;
+            }
+            // Processes may exit while being scanned
+            let matched = match (&comm, &exe) {
+                (Some(comm), _) => fs::read_to_string(format!("/proc/{}/comm", pid))
+                    .is_ok_and(|name| comm.is_match(name.trim_end())),
+                (_, Some(exe)) => fs::read_link(format!("/proc/{}/exe", pid))
+                    .is_ok_and(|path| exe.is_match(&path.to_string_lossy())),
+                _ => false,
+            };
+            if matched {
+                pids.push(pid);
+            }
+        }
+        pids.sort_unstable();
+        Ok(pids)
+    }
+
+    fn patterns(&self) -> io::Result<(Option<Regex>, Option<Regex>)> {
+        let compile = |pattern: &Option<String>| {
+            pattern
+                .as_deref()
+                .map(Regex::new)
+                .transpose()
+                .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))
+        };
+        Ok((compile(&self.comm)?, compile(&self.exe)?))
+    }
+}
+
+/// Paths, readable by the agent, of the ELF objects process `pid` has
+/// mapped executable, in order of first mapping.
+pub fn mapped_objects(pid: u32) -> io::Result<Vec<PathBuf>> {
+    let maps = proc_maps::get_process_maps(pid as proc_maps::Pid)?;
+    let mut seen = HashSet::new();
+    Ok(maps
+        .iter()
+        .filter(|map| map.is_exec())
+        .filter_map(|map| object_path(pid, map))
+        .filter(|path| seen.insert(path.clone()))
+        .collect())
+}
+
+/// Where the agent can open the file behind `map`. Paths in the maps file
+/// are relative to the process's root, which differs for containers; files
+/// deleted since they were mapped (e.g. upgraded in place) stay reachable
+/// through `map_files`.
+fn object_path(pid: u32, map: &MapRange) -> Option<PathBuf> {
+    // Anonymous mappings and pseudo files such as [vdso] have no path
+    let path = map.filename()?.to_str()?;
+    if !path.starts_with('/') {
+        return None;
+    }
+    if path.ends_with(DELETED_SUFFIX) {
+        return Some(PathBuf::from(format!(
+            "/proc/{}/map_files/{:x}-{:x}",
+            pid,
+            map.start(),
+            map.start() + map.size()
+        )));
+    }
+    Some(Path::new(&format!("/proc/{}/root", pid)).join(path.trim_start_matches('/')))
+}
+
+/// Field 22 of `/proc/<pid>/stat`, or `None` if the process does not exist.
+pub fn start_time(pid: u32) -> Option<u64> {
+    let stat = fs::read_to_string(format!("/proc/{}/stat", pid)).ok()?;
+    parse_start_time(&stat)
+}
+
+fn parse_start_time(stat: &str) -> Option<u64> {
+    // The command name in field 2 may contain spaces and parentheses, so
+    // fields are counted from the last closing parenthesis
+    let (_, fields) = stat.rsplit_once(')')?;
+    fields.split_whitespace().nth(19)?.parse().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn process_config(json: &str) -> ProcessConfig {
+        serde_json::from_str(json).unwrap()
+    }
+
+    #[test]
+    fn test_parse_process_entry() {
+        let config = process_config(
+            r#"{"comm": "^nginx$", "functions": ["ngx_http_process_request"], "symbol_tables": ["dynsym"]}"#,
+        );
+        config.validate().unwrap();
+        assert_eq!(config.comm.as_deref(), Some("^nginx$"));
+        assert_eq!(config.binary.functions, vec!["ngx_http_process_request"]);
+        assert!(config.binary.path.is_empty());
+    }
+
+    #[test]
+    fn test_exactly_one_selector_is_required() {
+        for json in [
+            r#"{"functions": []}"#,
+            r#"{"pid": 1, "comm": "init", "functions": []}"#,
+            r#"{"exe": "(unclosed", "functions": []}"#,
+        ] {
+            assert_eq!(
+                process_config(json).validate().unwrap_err().kind(),
+                io::ErrorKind::InvalidInput,
+                "{}",
+                json
+            );
+        }
+    }
+
+    #[test]
+    fn test_own_executable_is_mapped() {
+        let pid = std::process::id();
+        let exe = std::env::current_exe().unwrap();
+        let objects = mapped_objects(pid).unwrap();
+
+        let expected =
+            Path::new(&format!("/proc/{}/root", pid)).join(exe.strip_prefix("/").unwrap());
+        assert!(objects.contains(&expected), "{:?}", objects);
+        assert!(objects.iter().all(|path| path.exists()));
+    }
+
+    #[test]
+    fn test_comm_pattern_matches_other_processes() {
+        let mut child = std::process::Command::new("sleep")
+            .arg("10")
+            .spawn()
+            .unwrap();
+        let config = process_config(r#"{"comm": "^sleep$", "functions": []}"#);
+        // The child only takes its command name once it has exec'd
+        let found = (0..100).any(|_| {
+            std::thread::sleep(std::time::Duration::from_millis(10));
+            config.matching_pids().unwrap().contains(&child.id())
+        });
+        child.kill().unwrap();
+        child.wait().unwrap();
+
+        assert!(found);
+    }
+
+    #[test]
+    fn test_parse_start_time() {
+        let stat = "4242 (my (odd) app) S 1 4242 4242 0 -1 4194560 1197 0 0 0 12 3 0 0 20 0 4 0 987654 225017856 2521 18446744073709551615";
+        assert_eq!(parse_start_time(stat), Some(987654));
+        assert_eq!(parse_start_time("4242 (truncated"), None);
+    }
+}
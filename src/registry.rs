@@ -0,0 +1,114 @@
+use crate::offset_tracker::FunctionInfo;
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+
+/// A function with an attached probe, as seen by the event consumer.
+#[derive(Debug, Clone)]
+pub struct RegisteredFunction {
+    pub binary_path: String,
+    pub info: FunctionInfo,
+}
+
+impl RegisteredFunction {
+    /// Unqualified function name, e.g. `poll` for `tokio::runtime::poll`.
+    pub fn code_function(&self) -> &str {
+        self.split_name().1
+    }
+
+    /// Module path of the function, if its demangled name is qualified.
+    pub fn code_namespace(&self) -> Option<&str> {
+        self.split_name().0
+    }
+
+    /// Whether a call failed according to the function's return value rule.
+    pub fn is_error(&self, retval: u64) -> bool {
+        self.info
+            .return_value
+            .is_some_and(|return_value| return_value.is_error(retval))
+    }
+
+    fn split_name(&self) -> (Option<&str>, &str) {
+        match self.info.demangled_name.rsplit_once("::") {
+            Some((namespace, function)) => (Some(namespace), function),
+            None => (None, self.info.demangled_name.as_str()),
+        }
+    }
+}
+
+/// Maps the function id carried in each `BPFEvent` (the uprobe BPF cookie)
+/// back to the function it was attached to. Written by `Manager` when probes
+/// are attached, read by `Controller` for every event.
+#[derive(Debug, Clone, Default)]
+pub struct FunctionRegistry {
+    functions: Arc<RwLock<HashMap<u64, Arc<RegisteredFunction>>>>,
+}
+
+impl FunctionRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn insert(&self, function_id: u64, binary_path: &str, info: &FunctionInfo) {
+        let function = RegisteredFunction {
+            binary_path: binary_path.to_string(),
+            info: info.clone(),
+        };
+        self.functions
+            .write()
+            .unwrap()
+            .insert(function_id, Arc::new(function));
+    }
+
+    pub fn get(&self, function_id: u64) -> Option<Arc<RegisteredFunction>> {
+        self.functions.read().unwrap().get(&function_id).cloned()
+    }
+
+    pub fn remove(&self, function_id: u64) {
+        self.functions.write().unwrap().remove(&function_id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn function(demangled_name: &str) -> RegisteredFunction {
+        RegisteredFunction {
+            binary_path: "/usr/bin/app".to_string(),
+            info: FunctionInfo {
+                demangled_name: demangled_name.to_string(),
+                mangled_name: demangled_name.to_string(),
+                address: 0x401000,
+                offset: 0x1000,
+                ..Default::default()
+            },
+        }
+    }
+
+    #[test]
+    fn test_qualified_name_is_split_into_namespace_and_function() {
+        let function = function("myapp::handlers::get_user");
+        assert_eq!(function.code_namespace(), Some("myapp::handlers"));
+        assert_eq!(function.code_function(), "get_user");
+    }
+
+    #[test]
+    fn test_unqualified_name_has_no_namespace() {
+        let function = function("SSL_read");
+        assert_eq!(function.code_namespace(), None);
+        assert_eq!(function.code_function(), "SSL_read");
+    }
+
+    #[test]
+    fn test_lookup_by_function_id() {
+        let registry = FunctionRegistry::new();
+        let info = function("main").info;
+        registry.insert(7, "/usr/bin/app", &info);
+
+        assert_eq!(registry.get(7).unwrap().info.demangled_name, "main");
+        assert!(registry.get(8).is_none());
+
+        registry.remove(7);
+        assert!(registry.get(7).is_none());
+    }
+}
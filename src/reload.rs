@@ -0,0 +1,350 @@
+use crate::manager::ManagerHandle;
+use crate::offset_tracker::{FunctionInfo, InstrumentationConfig, OffsetTracker};
+use crate::sampling::SampleRate;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+use tokio::signal::unix::Signal;
+
+/// How often the config file's modification time is checked.
+const POLL_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Functions of the `binaries` entries, {binary: {function: info}}.
+pub type BinaryFunctions = HashMap<String, HashMap<String, FunctionInfo>>;
+
+/// A function of a `binaries` entry with an attached probe.
+#[derive(Debug, Clone)]
+pub struct AttachedFunction {
+    pub function_id: u64,
+    pub info: FunctionInfo,
+}
+
+/// What a new config changes in the attached functions. A function whose
+/// offset or capture settings changed is detached and attached again; a new
+/// sample rate only updates the BPF map.
+#[derive(Debug, Default)]
+pub struct ProbeChanges {
+    pub detach: Vec<(String, String)>,
+    pub attach: Vec<(String, FunctionInfo)>,
+    pub resample: Vec<((String, String), SampleRate)>,
+}
+
+impl ProbeChanges {
+    pub fn is_empty(&self) -> bool {
+        self.detach.is_empty() && self.attach.is_empty() && self.resample.is_empty()
+    }
+}
+
+/// Re-reads the config file when it is modified or the agent receives
+/// SIGHUP, and applies changes to the `binaries` entries without touching
+/// unchanged probes. Other sections only take effect on restart.
+pub struct ConfigReloader {
+    path: PathBuf,
+    modified: Option<SystemTime>,
+    /// The startup config, with the `binaries` and `debug_info` of the last
+    /// config that was applied. The other sections are kept as they were at
+    /// startup, so every reload warns about changes to them.
+    config: InstrumentationConfig,
+    /// Probes of the `binaries` entries, by binary path and function name.
+    attached: HashMap<(String, String), AttachedFunction>,
+    /// SIGHUP stream, created at startup so the signal never kills the agent.
+    hangup: Signal,
+}
+
+impl ConfigReloader {
+    pub fn new(
+        path: impl Into<PathBuf>,
+        config: InstrumentationConfig,
+        attached: HashMap<(String, String), AttachedFunction>,
+        hangup: Signal,
+    ) -> Self {
+        let path = path.into();
+        Self {
+            modified: modified(&path),
+            path,
+            config,
+            attached,
+            hangup,
+        }
+    }
+
+    /// Reloads on every change until the manager stops.
+    pub async fn run(mut self, manager: ManagerHandle) -> anyhow::Result<()> {
+        let mut ticker = tokio::time::interval(POLL_INTERVAL);
+        ticker.tick().await; // The first tick completes immediately
+
+        loop {
+            tokio::select! {
+                _ = ticker.tick() => {
+                    if modified(&self.path) == self.modified {
+                        continue;
+                    }
+                    println!("Config file {} changed, reloading", self.path.display());
+                }
+                _ = self.hangup.recv() => {
+                    println!("Received SIGHUP, reloading {}", self.path.display());
+                }
+            }
+            // Recorded before reading, so a broken file is reported once
+            // rather than on every poll
+            self.modified = modified(&self.path);
+
+            // Parsing ELF files blocks
+            let path = self.path.clone();
+            match tokio::task::spawn_blocking(move || load(&path)).await? {
+                Ok((config, functions)) => self.apply(config, &functions, &manager).await,
+                Err(err) => eprintln!(
+                    "❌ Error: Rejected config file '{}': {}. Keeping the current instrumentation.",
+                    self.path.display(),
+                    err
+                ),
+            }
+        }
+    }
+
+    async fn apply(
+        &mut self,
+        config: InstrumentationConfig,
+        functions: &BinaryFunctions,
+        manager: &ManagerHandle,
+    ) {
+        for section in restart_required(&self.config, &config) {
+            eprintln!(
+                "⚠️ Changes to '{}' take effect on restart, ignoring them",
+                section
+            );
+        }
+        self.config.binaries = config.binaries;
+        self.config.debug_info = config.debug_info;
+
+        let changes = diff(&self.attached, functions);
+        if changes.is_empty() {
+            println!("No instrumented function changed");
+            return;
+        }
+
+        for key in changes.detach {
+            let Some(attached) = self.attached.remove(&key) else {
+                continue;
+            };
+            match manager.unregister_probe(attached.function_id).await {
+                Ok(()) => println!(
+                    "Detached probe {} from {} in {}",
+                    attached.function_id, key.1, key.0
+                ),
+                Err(err) => eprintln!("⚠️ Failed to detach {} in {}: {}", key.1, key.0, err),
+            }
+        }
+        for ((binary, name), rate) in changes.resample {
+            let Some(attached) = self.attached.get_mut(&(binary.clone(), name.clone())) else {
+                continue;
+            };
+            match manager.set_sample_rate(attached.function_id, rate).await {
+                Ok(()) => {
+                    println!("Sampling {:?} of calls to {} in {}", rate, name, binary);
+                    attached.info.sample_rate = Some(rate);
+                }
+                Err(err) => eprintln!("⚠️ Failed to resample {} in {}: {}", name, binary, err),
+            }
+        }
+        for (binary, info) in changes.attach {
+            let name = info.demangled_name.clone();
+            match manager.register_probe(&binary, info.clone(), None).await {
+                Ok(function_id) => {
+                    println!(
+                        "🔍 Attached probe {} to {} in {} at {:#x}",
+                        function_id, name, binary, info.offset
+                    );
+                    self.attached
+                        .insert((binary, name), AttachedFunction { function_id, info });
+                }
+                // Not recorded as attached, so the next reload retries it
+                Err(err) => eprintln!("⚠️ Failed to attach to {} in {}: {}", name, binary, err),
+            }
+        }
+    }
+}
+
+/// Reads and validates the config at `path` and resolves the functions of
+/// its `binaries` entries. Any error rejects the whole config.
+fn load(path: &Path) -> io::Result<(InstrumentationConfig, BinaryFunctions)> {
+    let config = InstrumentationConfig::from_file(path)?;
+    let mut functions = HashMap::new();
+    for binary in &config.binaries {
+        let resolved = OffsetTracker::resolve_binary(binary, &config.debug_info)
+            .map_err(|err| io::Error::new(err.kind(), format!("{}: {}", binary.path, err)))?;
+        functions.insert(binary.path.clone(), resolved);
+    }
+    Ok((config, functions))
+}
+
+/// Compares the attached functions with those the new config resolves to.
+fn diff(
+    attached: &HashMap<(String, String), AttachedFunction>,
+    functions: &BinaryFunctions,
+) -> ProbeChanges {
+    let mut changes = ProbeChanges::default();
+    for (key, current) in attached {
+        let (binary, name) = key;
+        match functions
+            .get(binary)
+            .and_then(|functions| functions.get(name))
+        {
+            None => changes.detach.push(key.clone()),
+            Some(info) if *info == current.info => {}
+            Some(info) if without_sample_rate(info) == without_sample_rate(&current.info) => {
+                changes
+                    .resample
+                    .push((key.clone(), info.sample_rate.unwrap_or_default()));
+            }
+            Some(info) => {
+                changes.detach.push(key.clone());
+                changes.attach.push((binary.clone(), info.clone()));
+            }
+        }
+    }
+    for (binary, binary_functions) in functions {
+        for (name, info) in binary_functions {
+            if !attached.contains_key(&(binary.clone(), name.clone())) {
+                changes.attach.push((binary.clone(), info.clone()));
+            }
+        }
+    }
+    changes.detach.sort();
+    changes.attach.sort_by(|(a, a_info), (b, b_info)| {
+        (a, &a_info.demangled_name).cmp(&(b, &b_info.demangled_name))
+    });
+    changes.resample.sort_by(|(a, _), (b, _)| a.cmp(b));
+    changes
+}
+
+fn without_sample_rate(info: &FunctionInfo) -> FunctionInfo {
+    FunctionInfo {
+        sample_rate: None,
+        ..info.clone()
+    }
+}
+
+/// Config sections that differ between `old` and `new` but are only read at
+/// startup.
+fn restart_required(old: &InstrumentationConfig, new: &InstrumentationConfig) -> Vec<&'static str> {
+    fn differs<T: Serialize>(old: &T, new: &T) -> bool {
+        serde_json::to_value(old).ok() != serde_json::to_value(new).ok()
+    }
+
+    [
+        ("processes", differs(&old.processes, &new.processes)),
+        ("exporter", differs(&old.exporter, &new.exporter)),
+        ("metrics", differs(&old.metrics, &new.metrics)),
+        (
+            "tail_sampling",
+            differs(&old.tail_sampling, &new.tail_sampling),
+        ),
+        ("filter", differs(&old.filter, &new.filter)),
+        ("watch", differs(&old.watch, &new.watch)),
+        ("events", differs(&old.events, &new.events)),
+    ]
+    .into_iter()
+    .filter_map(|(section, changed)| changed.then_some(section))
+    .collect()
+}
+
+fn modified(path: &Path) -> Option<SystemTime> {
+    fs::metadata(path)
+        .and_then(|metadata| metadata.modified())
+        .ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn function(name: &str, offset: u64) -> FunctionInfo {
+        FunctionInfo {
+            demangled_name: name.to_string(),
+            mangled_name: name.to_string(),
+            address: offset,
+            offset,
+            ..Default::default()
+        }
+    }
+
+    fn key(binary: &str, name: &str) -> (String, String) {
+        (binary.to_string(), name.to_string())
+    }
+
+    #[test]
+    fn test_only_changed_functions_are_reattached() {
+        let attached: HashMap<_, _> = [
+            ("kept", function("kept", 0x10)),
+            ("removed", function("removed", 0x20)),
+            ("moved", function("moved", 0x30)),
+            ("resampled", function("resampled", 0x40)),
+        ]
+        .into_iter()
+        .enumerate()
+        .map(|(index, (name, info))| {
+            let function_id = index as u64 + 1;
+            (
+                key("/bin/app", name),
+                AttachedFunction { function_id, info },
+            )
+        })
+        .collect();
+
+        let mut resampled = function("resampled", 0x40);
+        resampled.sample_rate = Some(SampleRate::Probability(0.5));
+        let functions: BinaryFunctions = HashMap::from([(
+            "/bin/app".to_string(),
+            HashMap::from([
+                ("kept".to_string(), function("kept", 0x10)),
+                ("moved".to_string(), function("moved", 0x38)),
+                ("resampled".to_string(), resampled),
+                ("added".to_string(), function("added", 0x50)),
+            ]),
+        )]);
+
+        let changes = diff(&attached, &functions);
+        assert_eq!(
+            changes.detach,
+            vec![key("/bin/app", "moved"), key("/bin/app", "removed")]
+        );
+        assert_eq!(
+            changes.attach,
+            vec![
+                ("/bin/app".to_string(), function("added", 0x50)),
+                ("/bin/app".to_string(), function("moved", 0x38)),
+            ]
+        );
+        assert_eq!(
+            changes.resample,
+            vec![(key("/bin/app", "resampled"), SampleRate::Probability(0.5))]
+        );
+        assert!(diff(&HashMap::new(), &HashMap::new()).is_empty());
+    }
+
+    #[test]
+    fn test_invalid_configs_are_rejected() {
+        let path = std::env::temp_dir().join("reload_test_config.json");
+        for json in [
+            r#"{"binaries": ["#,
+            r#"{"binaries": [{"functions": ["main"]}]}"#,
+            r#"{"binaries": [{"path": "/nonexistent/app", "functions": ["main"]}]}"#,
+        ] {
+            fs::write(&path, json).unwrap();
+            assert!(load(&path).is_err(), "{}", json);
+        }
+
+        fs::write(&path, r#"{"watch": {"enabled": true}}"#).unwrap();
+        let (config, functions) = load(&path).unwrap();
+        assert!(functions.is_empty());
+        assert_eq!(
+            restart_required(&InstrumentationConfig::default(), &config),
+            vec!["watch"]
+        );
+        let _ = fs::remove_file(&path);
+    }
+}
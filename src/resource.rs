@@ -0,0 +1,398 @@
+use crate::exporter::{self, ExporterConfig};
+use crate::process;
+use anyhow::Result;
+use opentelemetry::trace::TracerProvider as _;
+use opentelemetry::KeyValue;
+use opentelemetry_sdk::trace::{Tracer, TracerProvider};
+use opentelemetry_sdk::Resource;
+use regex::Regex;
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+use std::sync::{Arc, LazyLock, Mutex};
+use std::time::Instant;
+
+// Container ids are the last cgroup path component, optionally wrapped by
+// the runtime: `docker-<id>.scope`, `cri-containerd-<id>.scope`, `libpod-<id>.scope`
+static CONTAINER_ID_REGEX: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"(?:^|[/-])([0-9a-f]{64})(?:\.scope)?$").unwrap());
+
+/// Extracts the container id from the contents of `/proc/<pid>/cgroup`.
+pub fn container_id(cgroup: &str) -> Option<String> {
+    cgroup.lines().find_map(|line| {
+        // hierarchy-ID:controllers:path
+        let path = line.splitn(3, ':').nth(2)?;
+        let caps = CONTAINER_ID_REGEX.captures(path)?;
+        Some(caps[1].to_string())
+    })
+}
+
+/// Name of the host the agent runs on, for the `host.name` attribute.
+pub fn host_name() -> Option<String> {
+    nix::unistd::gethostname()
+        .ok()
+        .map(|name| name.to_string_lossy().into_owned())
+}
+
+/// Turns the NUL-separated `/proc/<pid>/cmdline` into a single command line.
+pub fn command_line(cmdline: &[u8]) -> String {
+    cmdline
+        .split(|&b| b == 0)
+        .filter(|arg| !arg.is_empty())
+        .map(|arg| String::from_utf8_lossy(arg))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Executable of process `pid`, as the process sees it. Read from
+/// `/proc/<pid>/exe`, so it does not depend on which object (e.g. a shared
+/// library) a span came from; `fallback` is used if the link cannot be read.
+pub fn executable_path(pid: u32, fallback: Option<&str>) -> String {
+    let path = fs::read_link(format!("/proc/{}/exe", pid))
+        .map(|path| path.to_string_lossy().into_owned())
+        .ok()
+        .or_else(|| fallback.map(str::to_string))
+        .unwrap_or_default();
+    strip_process_root(pid, &path)
+}
+
+/// Removes the `/proc/<pid>/root` prefix of paths opened through the
+/// process's root (e.g. in a container) and the kernel's ` (deleted)` mark.
+fn strip_process_root(pid: u32, path: &str) -> String {
+    let path = path.strip_suffix(" (deleted)").unwrap_or(path);
+    match path.strip_prefix(&format!("/proc/{}/root", pid)) {
+        Some(relative) if relative.starts_with('/') => relative.to_string(),
+        _ => path.to_string(),
+    }
+}
+
+/// Resource describing one traced process. `executable_path` is the
+/// process's executable; `service_name` defaults to its file name.
+pub fn process_resource(
+    pid: u32,
+    executable_path: &str,
+    service_name: Option<&str>,
+    host_name: Option<&str>,
+) -> Resource {
+    let service_name = service_name.map(str::to_string).unwrap_or_else(|| {
+        Path::new(executable_path)
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or_else(|| executable_path.to_string())
+    });
+
+    let mut attributes = vec![
+        KeyValue::new("service.name", service_name),
+        KeyValue::new("process.pid", pid as i64),
+        KeyValue::new("process.executable.path", executable_path.to_string()),
+    ];
+    if let Ok(cmdline) = fs::read(format!("/proc/{}/cmdline", pid)) {
+        attributes.push(KeyValue::new(
+            "process.command_line",
+            command_line(&cmdline),
+        ));
+    }
+    if let Some(host_name) = host_name {
+        attributes.push(KeyValue::new("host.name", host_name.to_string()));
+    }
+    if let Some(container_id) = fs::read_to_string(format!("/proc/{}/cgroup", pid))
+        .ok()
+        .and_then(|cgroup| container_id(&cgroup))
+    {
+        attributes.push(KeyValue::new("container.id", container_id));
+    }
+
+    Resource::new_with_defaults(attributes)
+}
+
+/// The provider of one traced process.
+struct ProcessTracer {
+    provider: TracerProvider,
+    tracer: Tracer,
+    /// Start time of the process, from `/proc/<pid>/stat`, to tell it from a
+    /// later process with the same pid. `None` if it had already exited.
+    start_time: Option<u64>,
+    last_used: Instant,
+}
+
+/// One tracer provider per traced process, so that each process's spans
+/// carry its own resource. Providers are created on the first span of a
+/// process and share the exporter configuration. Each holds its own exporter
+/// connection, so at most `exporter.max_processes` are kept; the least
+/// recently used one is shut down to make room.
+#[derive(Clone)]
+pub struct ProcessTracers {
+    exporter: Arc<ExporterConfig>,
+    host_name: Option<String>,
+    providers: Arc<Mutex<HashMap<u32, ProcessTracer>>>,
+}
+
+impl ProcessTracers {
+    pub fn new(exporter: ExporterConfig) -> Self {
+        Self {
+            exporter: Arc::new(exporter),
+            host_name: host_name(),
+            providers: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// Tracer for `pid`. `binary_path` is the instrumented object the span
+    /// comes from, if known, and only stands in for the executable when
+    /// `/proc/<pid>/exe` cannot be read. `service_name` is the configured
+    /// one, if any.
+    pub fn tracer(
+        &self,
+        pid: u32,
+        binary_path: Option<&str>,
+        service_name: Option<&str>,
+    ) -> Result<Tracer> {
+        if let Some(process) = self.providers.lock().unwrap().get_mut(&pid) {
+            process.last_used = Instant::now();
+            return Ok(process.tracer.clone());
+        }
+
+        // Reading /proc and building the exporter are slow, so spans of
+        // other processes are not held up meanwhile
+        let start_time = process::start_time(pid);
+        let executable_path = executable_path(pid, binary_path);
+        let resource = process_resource(
+            pid,
+            &executable_path,
+            service_name,
+            self.host_name.as_deref(),
+        );
+        println!("Creating tracer provider for pid {}: {:?}", pid, resource);
+        let provider = exporter::build_tracer_provider(&self.exporter, resource)?;
+        let tracer = provider.tracer("ebpf_tracer");
+
+        let mut providers = self.providers.lock().unwrap();
+        if !providers.contains_key(&pid) && providers.len() >= self.exporter.max_processes {
+            let least_recent = providers
+                .iter()
+                .min_by_key(|(_, process)| process.last_used)
+                .map(|(&pid, _)| pid);
+            if let Some((evicted_pid, evicted)) =
+                least_recent.and_then(|pid| Some((pid, providers.remove(&pid)?)))
+            {
+                println!(
+                    "Tracing {} processes, shutting down the tracer provider of pid {}",
+                    self.exporter.max_processes, evicted_pid
+                );
+                // Shutting down exports what is queued, which must not hold
+                // up the caller
+                std::thread::spawn(move || shutdown_provider(evicted_pid, evicted.provider));
+            }
+        }
+        let mut created = Some(provider);
+        let process = providers.entry(pid).or_insert_with(|| ProcessTracer {
+            provider: created.take().unwrap(),
+            tracer,
+            start_time,
+            last_used: Instant::now(),
+        });
+        process.last_used = Instant::now();
+        let tracer = process.tracer.clone();
+        drop(providers);
+
+        // Another span of the process created its provider first
+        if let Some(unused) = created {
+            std::thread::spawn(move || shutdown_provider(pid, unused));
+        }
+        Ok(tracer)
+    }
+
+    /// Flushes and shuts down the providers of processes that have exited.
+    /// A pid now used by another process counts as exited, so that the new
+    /// process gets a provider with its own resource.
+    pub fn remove_exited(&self) {
+        let tracked: Vec<(u32, Option<u64>)> = self
+            .providers
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(&pid, process)| (pid, process.start_time))
+            .collect();
+        let exited: Vec<(u32, Option<u64>)> = tracked
+            .into_iter()
+            .filter(|&(pid, started)| started.is_none() || process::start_time(pid) != started)
+            .collect();
+        let removed: Vec<(u32, TracerProvider)> = {
+            let mut providers = self.providers.lock().unwrap();
+            exited
+                .into_iter()
+                .filter_map(|(pid, started)| {
+                    // Skips providers replaced since the scan
+                    if providers.get(&pid)?.start_time != started {
+                        return None;
+                    }
+                    Some((pid, providers.remove(&pid)?.provider))
+                })
+                .collect()
+        };
+        // Shutting down exports what is queued, so the lock is not held
+        for (pid, provider) in removed {
+            println!("Process {} exited, shutting down its tracer provider", pid);
+            shutdown_provider(pid, provider);
+        }
+    }
+
+    /// Flushes and shuts down every provider.
+    pub fn shutdown(&self) {
+        for (pid, process) in self.providers.lock().unwrap().drain() {
+            shutdown_provider(pid, process.provider);
+        }
+    }
+}
+
+fn shutdown_provider(pid: u32, provider: TracerProvider) {
+    if let Err(err) = provider.shutdown() {
+        eprintln!(
+            "Failed to shut down tracer provider for pid {}: {}",
+            pid, err
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ID: &str = "3f4b1c1e0c5a9f2d8e7b6a5c4d3e2f1a0b9c8d7e6f5a4b3c2d1e0f9a8b7c6d5e";
+
+    #[test]
+    fn test_container_id_from_cgroup_v1_docker() {
+        let cgroup = format!(
+            "12:pids:/docker/{id}\n11:cpu,cpuacct:/docker/{id}\n0::/",
+            id = ID
+        );
+        assert_eq!(container_id(&cgroup).as_deref(), Some(ID));
+    }
+
+    #[test]
+    fn test_container_id_from_cgroup_v2_scopes() {
+        for path in [
+            format!("/system.slice/docker-{}.scope", ID),
+            format!(
+                "/kubepods.slice/kubepods-besteffort.slice/kubepods-besteffort-pod1234.slice/cri-containerd-{}.scope",
+                ID
+            ),
+            format!("/kubepods/burstable/pod5678/{}", ID),
+        ] {
+            let cgroup = format!("0::{}", path);
+            assert_eq!(container_id(&cgroup).as_deref(), Some(ID), "{}", path);
+        }
+    }
+
+    #[test]
+    fn test_no_container_id_outside_containers() {
+        assert_eq!(
+            container_id("0::/user.slice/user-1000.slice/session-2.scope"),
+            None
+        );
+        assert_eq!(container_id(""), None);
+    }
+
+    #[test]
+    fn test_command_line_joins_arguments() {
+        assert_eq!(
+            command_line(b"/usr/bin/server\0--port\08080\0"),
+            "/usr/bin/server --port 8080"
+        );
+    }
+
+    #[test]
+    fn test_process_resource_attributes() {
+        let resource = process_resource(
+            std::process::id(),
+            "/opt/myapp/bin/server",
+            None,
+            Some("node-1"),
+        );
+        let get = |key: &'static str| resource.get(key.into()).map(|value| value.to_string());
+
+        assert_eq!(get("service.name").as_deref(), Some("server"));
+        assert_eq!(
+            get("process.executable.path").as_deref(),
+            Some("/opt/myapp/bin/server")
+        );
+        assert_eq!(get("host.name").as_deref(), Some("node-1"));
+        assert!(get("process.command_line").is_some());
+    }
+
+    #[test]
+    fn test_executable_path_ignores_probed_object() {
+        let pid = std::process::id();
+        let exe = std::env::current_exe().unwrap();
+        assert_eq!(
+            executable_path(pid, Some("/usr/lib/x86_64-linux-gnu/libssl.so.3")),
+            exe.to_string_lossy()
+        );
+
+        // Process mode paths of a process that is gone
+        let exited_pid = 1 << 23;
+        assert_eq!(
+            executable_path(
+                exited_pid,
+                Some(&format!("/proc/{}/root/usr/bin/app (deleted)", exited_pid))
+            ),
+            "/usr/bin/app"
+        );
+        assert_eq!(
+            strip_process_root(42, "/proc/420/root/app"),
+            "/proc/420/root/app"
+        );
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_providers_of_exited_processes_are_removed() {
+        let tracers = ProcessTracers::new(ExporterConfig::default());
+        // Above the kernel's pid_max, so never a running process
+        let exited_pid = 1 << 23;
+        tracers.tracer(std::process::id(), None, None).unwrap();
+        tracers
+            .tracer(exited_pid, Some("/usr/bin/app"), Some("app"))
+            .unwrap();
+
+        tracers.remove_exited();
+
+        let providers = tracers.providers.lock().unwrap();
+        assert!(providers.contains_key(&std::process::id()));
+        assert!(!providers.contains_key(&exited_pid));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_provider_of_reused_pid_is_removed() {
+        let tracers = ProcessTracers::new(ExporterConfig::default());
+        let pid = std::process::id();
+        tracers.tracer(pid, None, None).unwrap();
+        // As if the provider was created for an earlier process with this pid
+        tracers
+            .providers
+            .lock()
+            .unwrap()
+            .get_mut(&pid)
+            .unwrap()
+            .start_time = Some(1);
+
+        tracers.remove_exited();
+
+        assert!(!tracers.providers.lock().unwrap().contains_key(&pid));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_least_recently_used_provider_is_evicted() {
+        let tracers = ProcessTracers::new(ExporterConfig {
+            max_processes: 2,
+            ..Default::default()
+        });
+        tracers.tracer(100, Some("/usr/bin/a"), None).unwrap();
+        tracers.tracer(200, Some("/usr/bin/b"), None).unwrap();
+        tracers.tracer(100, Some("/usr/bin/a"), None).unwrap();
+        tracers.tracer(300, Some("/usr/bin/c"), None).unwrap();
+
+        let providers = tracers.providers.lock().unwrap();
+        let mut pids: Vec<u32> = providers.keys().copied().collect();
+        pids.sort_unstable();
+        assert_eq!(pids, vec![100, 300]);
+    }
+}
@@ -0,0 +1,92 @@
+use serde::{Deserialize, Serialize};
+use std::io;
+
+/// Share of a function's calls that produce events, written as a
+/// probability (`0.01`) or a 1-in-N rate (`{"one_in": 100}`). The decision is
+/// made in the BPF program before a ring buffer slot is reserved.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum SampleRate {
+    Probability(f64),
+    OneIn { one_in: u32 },
+}
+
+impl SampleRate {
+    pub fn validate(&self) -> io::Result<()> {
+        let valid = match *self {
+            SampleRate::Probability(probability) => probability > 0.0 && probability <= 1.0,
+            SampleRate::OneIn { one_in } => one_in > 0,
+        };
+        if valid {
+            Ok(())
+        } else {
+            Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "Invalid sample rate {:?}: expected a probability in (0, 1] or one_in >= 1",
+                    self
+                ),
+            ))
+        }
+    }
+
+    /// Value stored in the `sample_thresholds` map: a call is sampled when a
+    /// random `u32` is below it, so `1 << 32` keeps every call. Never zero, so
+    /// a configured function always keeps some calls.
+    pub fn threshold(&self) -> u64 {
+        let probability = match *self {
+            SampleRate::Probability(probability) => probability,
+            SampleRate::OneIn { one_in } => 1.0 / one_in as f64,
+        };
+        ((probability.clamp(0.0, 1.0) * (1u64 << 32) as f64).round() as u64).clamp(1, 1 << 32)
+    }
+}
+
+impl Default for SampleRate {
+    fn default() -> Self {
+        SampleRate::Probability(1.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_probability_and_one_in() {
+        let rates: Vec<SampleRate> = serde_json::from_str(r#"[0.25, {"one_in": 100}]"#).unwrap();
+        assert_eq!(
+            rates,
+            vec![
+                SampleRate::Probability(0.25),
+                SampleRate::OneIn { one_in: 100 }
+            ]
+        );
+    }
+
+    #[test]
+    fn test_thresholds() {
+        assert_eq!(SampleRate::default().threshold(), 1 << 32);
+        assert_eq!(SampleRate::Probability(0.25).threshold(), 1 << 30);
+        assert_eq!(SampleRate::OneIn { one_in: 1 }.threshold(), 1 << 32);
+        assert_eq!(SampleRate::OneIn { one_in: 4 }.threshold(), 1 << 30);
+        assert_eq!(SampleRate::Probability(1e-12).threshold(), 1);
+    }
+
+    #[test]
+    fn test_out_of_range_rates_are_rejected() {
+        for rate in [
+            SampleRate::Probability(0.0),
+            SampleRate::Probability(1.5),
+            SampleRate::Probability(f64::NAN),
+            SampleRate::OneIn { one_in: 0 },
+        ] {
+            assert_eq!(
+                rate.validate().unwrap_err().kind(),
+                io::ErrorKind::InvalidInput,
+                "{:?}",
+                rate
+            );
+        }
+    }
+}
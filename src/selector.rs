@@ -0,0 +1,229 @@
+use crate::offset_tracker::ElfSymbol;
+use regex::Regex;
+use std::collections::HashSet;
+use std::io;
+
+/// Prefix marking a selector as a regular expression.
+const REGEX_PREFIX: &str = "regex:";
+/// Prefix marking a selector as an exclusion.
+const EXCLUDE_PREFIX: &str = "!";
+
+/// Matches demangled function names against one entry of
+/// `BinaryConfig::functions`.
+#[derive(Debug)]
+enum Matcher {
+    /// A plain function name, e.g. `myapp::handlers::get_user`.
+    Exact(String),
+    /// A glob (`myapp::handlers::*`) or `regex:` selector.
+    Pattern(Regex),
+}
+
+#[derive(Debug)]
+struct Selector {
+    source: String,
+    matcher: Matcher,
+}
+
+impl Selector {
+    fn parse(source: &str) -> io::Result<Self> {
+        let matcher = if let Some(pattern) = source.strip_prefix(REGEX_PREFIX) {
+            Matcher::Pattern(compile(source, pattern)?)
+        } else if source.contains(['*', '?']) {
+            Matcher::Pattern(compile(source, &glob_to_regex(source))?)
+        } else {
+            Matcher::Exact(source.to_string())
+        };
+
+        Ok(Self {
+            source: source.to_string(),
+            matcher,
+        })
+    }
+
+    fn matches(&self, name: &str) -> bool {
+        match &self.matcher {
+            Matcher::Exact(exact) => exact == name,
+            Matcher::Pattern(regex) => regex.is_match(name),
+        }
+    }
+}
+
+/// The function selectors configured for one binary: exact names, globs,
+/// `regex:` patterns, and `!`-prefixed exclusions of any of those.
+#[derive(Debug)]
+pub struct FunctionSelectors {
+    includes: Vec<Selector>,
+    excludes: Vec<Selector>,
+}
+
+impl FunctionSelectors {
+    pub fn parse(entries: &[String]) -> io::Result<Self> {
+        let mut includes = Vec::new();
+        let mut excludes = Vec::new();
+
+        for entry in entries {
+            match entry.strip_prefix(EXCLUDE_PREFIX) {
+                Some(excluded) => excludes.push(Selector::parse(excluded)?),
+                None => includes.push(Selector::parse(entry)?),
+            }
+        }
+
+        Ok(Self { includes, excludes })
+    }
+
+    /// Expands the selectors against `symbols`. Fails if a single selector
+    /// matches more than `max_matches` functions, which usually means the
+    /// pattern is broader than intended.
+    pub fn select<'a>(
+        &self,
+        symbols: &'a [ElfSymbol],
+        max_matches: usize,
+    ) -> io::Result<Vec<&'a ElfSymbol>> {
+        let mut selected = Vec::new();
+        let mut seen = HashSet::new();
+
+        for selector in &self.includes {
+            let matches: Vec<&ElfSymbol> = symbols
+                .iter()
+                .filter(|sym| selector.matches(&sym.demangled_name))
+                .filter(|sym| !self.is_excluded(&sym.demangled_name))
+                .collect();
+
+            if matches.len() > max_matches {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!(
+                        "selector '{}' matched {} functions, more than the limit of {}",
+                        selector.source,
+                        matches.len(),
+                        max_matches
+                    ),
+                ));
+            }
+
+            for sym in matches {
+                if seen.insert(sym.mangled_name.as_str()) {
+                    selected.push(sym);
+                }
+            }
+        }
+
+        Ok(selected)
+    }
+
+    fn is_excluded(&self, name: &str) -> bool {
+        self.excludes.iter().any(|selector| selector.matches(name))
+    }
+}
+
+fn compile(source: &str, pattern: &str) -> io::Result<Regex> {
+    Regex::new(pattern).map_err(|e| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("invalid function selector '{}': {}", source, e),
+        )
+    })
+}
+
+/// Converts a glob to an anchored regex: `*` matches any run of characters
+/// (including `::`), `?` matches a single character.
+fn glob_to_regex(glob: &str) -> String {
+    let mut pattern = String::from("^");
+    for c in glob.chars() {
+        match c {
+            '*' => pattern.push_str(".*"),
+            '?' => pattern.push('.'),
+            c => pattern.push_str(&regex::escape(&c.to_string())),
+        }
+    }
+    pattern.push('$');
+    pattern
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn symbols(names: &[&str]) -> Vec<ElfSymbol> {
+        names
+            .iter()
+            .enumerate()
+            .map(|(i, name)| ElfSymbol {
+                mangled_name: format!("_ZN{}", name),
+                demangled_name: name.to_string(),
+                address: 0x1000 + i as u64 * 0x10,
+            })
+            .collect()
+    }
+
+    fn select(entries: &[&str], names: &[&str], max_matches: usize) -> io::Result<Vec<String>> {
+        let entries: Vec<String> = entries.iter().map(|s| s.to_string()).collect();
+        let symbols = symbols(names);
+        let selectors = FunctionSelectors::parse(&entries)?;
+        Ok(selectors
+            .select(&symbols, max_matches)?
+            .into_iter()
+            .map(|sym| sym.demangled_name.clone())
+            .collect())
+    }
+
+    const NAMES: &[&str] = &[
+        "myapp::handlers::get_user",
+        "myapp::handlers::admin::delete_user",
+        "myapp::db::query",
+        "tokio::runtime::task::poll",
+        "tokio::runtime::task::poll_future",
+    ];
+
+    #[test]
+    fn test_exact_name() {
+        let selected = select(&["myapp::db::query"], NAMES, 10).unwrap();
+        assert_eq!(selected, vec!["myapp::db::query"]);
+    }
+
+    #[test]
+    fn test_glob_matches_nested_modules() {
+        let selected = select(&["myapp::handlers::*"], NAMES, 10).unwrap();
+        assert_eq!(
+            selected,
+            vec![
+                "myapp::handlers::get_user",
+                "myapp::handlers::admin::delete_user"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_regex_selector() {
+        let selected = select(&["regex:^tokio::.*poll$"], NAMES, 10).unwrap();
+        assert_eq!(selected, vec!["tokio::runtime::task::poll"]);
+    }
+
+    #[test]
+    fn test_exclusions_apply_to_all_selectors() {
+        let selected = select(&["myapp::*", "!*::admin::*"], NAMES, 10).unwrap();
+        assert_eq!(
+            selected,
+            vec!["myapp::handlers::get_user", "myapp::db::query"]
+        );
+    }
+
+    #[test]
+    fn test_overlapping_selectors_select_once() {
+        let selected = select(&["myapp::db::*", "regex:query$"], NAMES, 10).unwrap();
+        assert_eq!(selected, vec!["myapp::db::query"]);
+    }
+
+    #[test]
+    fn test_selector_over_match_limit_is_rejected() {
+        let err = select(&["*"], NAMES, 3).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        assert!(err.to_string().contains("matched 5 functions"));
+    }
+
+    #[test]
+    fn test_invalid_regex_is_rejected() {
+        let err = select(&["regex:(unclosed"], NAMES, 10).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    }
+}
@@ -0,0 +1,416 @@
+use crate::controller::CallSpan;
+use opentelemetry::trace::Status;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, VecDeque};
+use std::io;
+use std::time::{Duration, Instant};
+
+/// Traces whose decision is remembered, so spans arriving after it (e.g.
+/// from a call that outlived a timed-out trace) follow it.
+const DECISION_CACHE_SIZE: usize = 10_000;
+
+fn default_max_buffered_spans() -> usize {
+    10_000
+}
+
+fn default_trace_timeout_secs() -> u64 {
+    30
+}
+
+/// Condition on a single span. Every field that is set must hold; a trace is
+/// kept when any of its spans matches any rule.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct TailSamplingRule {
+    /// Spans at least this long.
+    #[serde(default)]
+    pub min_duration_ms: Option<u64>,
+    /// Spans whose return value matched the function's error rule.
+    #[serde(default)]
+    pub error: Option<bool>,
+    /// Span attributes with these exact values, e.g. `{"code.function": "checkout"}`.
+    #[serde(default)]
+    pub attributes: HashMap<String, String>,
+}
+
+impl TailSamplingRule {
+    fn matches(&self, span: &CallSpan) -> bool {
+        self.min_duration_ms
+            .is_none_or(|min| span.duration() >= Duration::from_millis(min))
+            && self
+                .error
+                .is_none_or(|error| matches!(span.status, Status::Error { .. }) == error)
+            && self.attributes.iter().all(|(key, value)| {
+                span.attributes
+                    .iter()
+                    .any(|kv| kv.key.as_str() == key && kv.value.as_str() == value.as_str())
+            })
+    }
+
+    fn is_empty(&self) -> bool {
+        self.min_duration_ms.is_none() && self.error.is_none() && self.attributes.is_empty()
+    }
+}
+
+/// Decides which traces are exported once they complete, so slow or failed
+/// calls are always kept while fast, successful ones are sampled.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TailSamplingConfig {
+    #[serde(default)]
+    pub enabled: bool,
+    #[serde(default)]
+    pub rules: Vec<TailSamplingRule>,
+    /// Share of the traces matching no rule that are kept anyway.
+    #[serde(default)]
+    pub probability: f64,
+    /// Spans held while waiting for their trace to complete. The oldest
+    /// trace is decided early when the budget is exceeded.
+    #[serde(default = "default_max_buffered_spans")]
+    pub max_buffered_spans: usize,
+    /// Traces whose root has not returned after this long are decided with
+    /// the spans seen so far.
+    #[serde(default = "default_trace_timeout_secs")]
+    pub trace_timeout_secs: u64,
+}
+
+impl Default for TailSamplingConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            rules: Vec::new(),
+            probability: 0.0,
+            max_buffered_spans: default_max_buffered_spans(),
+            trace_timeout_secs: default_trace_timeout_secs(),
+        }
+    }
+}
+
+impl TailSamplingConfig {
+    pub fn validate(&self) -> io::Result<()> {
+        let invalid = |reason: &str| {
+            Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("tail_sampling: {}", reason),
+            ))
+        };
+        if !(0.0..=1.0).contains(&self.probability) {
+            return invalid("probability must be between 0 and 1");
+        }
+        if self.max_buffered_spans == 0 || self.trace_timeout_secs == 0 {
+            return invalid("max_buffered_spans and trace_timeout_secs must be positive");
+        }
+        if self.rules.iter().any(TailSamplingRule::is_empty) {
+            return invalid("every rule needs at least one condition");
+        }
+        Ok(())
+    }
+}
+
+struct PendingTrace {
+    first_seen: Instant,
+    spans: Vec<CallSpan>,
+}
+
+/// Buffers the spans of each trace until its root span completes, then
+/// exports or drops them together.
+pub struct TailSampler {
+    config: TailSamplingConfig,
+    pending: HashMap<u128, PendingTrace>,
+    /// Pending traces by arrival, for timeouts and eviction. Entries whose
+    /// trace was already decided are skipped.
+    arrivals: VecDeque<(u128, Instant)>,
+    buffered_spans: usize,
+    /// Whether recently decided traces were kept.
+    decisions: HashMap<u128, bool>,
+    decision_order: VecDeque<u128>,
+}
+
+impl TailSampler {
+    pub fn new(config: TailSamplingConfig) -> Self {
+        Self {
+            config,
+            pending: HashMap::new(),
+            arrivals: VecDeque::new(),
+            buffered_spans: 0,
+            decisions: HashMap::new(),
+            decision_order: VecDeque::new(),
+        }
+    }
+
+    /// Takes a completed call and returns the spans to export now.
+    pub fn add(&mut self, span: CallSpan, now: Instant) -> Vec<CallSpan> {
+        let trace_id = span.trace_id;
+        match self.decisions.get(&trace_id) {
+            Some(true) => return vec![span],
+            Some(false) => return Vec::new(),
+            None => {}
+        }
+
+        let is_root = span.parent_span_id == 0;
+        self.pending
+            .entry(trace_id)
+            .or_insert_with(|| {
+                self.arrivals.push_back((trace_id, now));
+                PendingTrace {
+                    first_seen: now,
+                    spans: Vec::new(),
+                }
+            })
+            .spans
+            .push(span);
+        self.buffered_spans += 1;
+
+        // Nested calls return before the call enclosing them, so the whole
+        // trace has been seen once its root returns
+        let mut export = if is_root {
+            self.decide(trace_id)
+        } else {
+            Vec::new()
+        };
+        while self.buffered_spans > self.config.max_buffered_spans {
+            match self.oldest_pending() {
+                Some(oldest) => export.extend(self.decide(oldest)),
+                None => break,
+            }
+        }
+        export
+    }
+
+    /// Decides the traces that have waited longer than the timeout.
+    pub fn expire(&mut self, now: Instant) -> Vec<CallSpan> {
+        let timeout = Duration::from_secs(self.config.trace_timeout_secs);
+        let mut export = Vec::new();
+        while let Some(&(trace_id, first_seen)) = self.arrivals.front() {
+            if self.is_pending(trace_id, first_seen) && now.duration_since(first_seen) < timeout {
+                break;
+            }
+            self.arrivals.pop_front();
+            if self.is_pending(trace_id, first_seen) {
+                export.extend(self.decide(trace_id));
+            }
+        }
+        export
+    }
+
+    /// Decides every pending trace, e.g. on shutdown.
+    pub fn flush(&mut self) -> Vec<CallSpan> {
+        let trace_ids: Vec<u128> = self.pending.keys().copied().collect();
+        trace_ids
+            .into_iter()
+            .flat_map(|trace_id| self.decide(trace_id))
+            .collect()
+    }
+
+    fn is_pending(&self, trace_id: u128, first_seen: Instant) -> bool {
+        self.pending
+            .get(&trace_id)
+            .is_some_and(|trace| trace.first_seen == first_seen)
+    }
+
+    fn oldest_pending(&mut self) -> Option<u128> {
+        while let Some((trace_id, first_seen)) = self.arrivals.pop_front() {
+            if self.is_pending(trace_id, first_seen) {
+                return Some(trace_id);
+            }
+        }
+        None
+    }
+
+    fn decide(&mut self, trace_id: u128) -> Vec<CallSpan> {
+        let Some(trace) = self.pending.remove(&trace_id) else {
+            return Vec::new();
+        };
+        self.buffered_spans -= trace.spans.len();
+
+        let keep = trace
+            .spans
+            .iter()
+            .any(|span| self.config.rules.iter().any(|rule| rule.matches(span)))
+            || self.sampled_by_probability(trace_id);
+        self.remember(trace_id, keep);
+        if keep {
+            trace.spans
+        } else {
+            Vec::new()
+        }
+    }
+
+    /// Trace ids are random, so their low bits make a consistent coin flip.
+    fn sampled_by_probability(&self, trace_id: u128) -> bool {
+        let threshold = (self.config.probability * u64::MAX as f64) as u64;
+        self.config.probability > 0.0 && (trace_id as u64) <= threshold
+    }
+
+    fn remember(&mut self, trace_id: u128, keep: bool) {
+        if self.decision_order.len() == DECISION_CACHE_SIZE {
+            if let Some(oldest) = self.decision_order.pop_front() {
+                self.decisions.remove(&oldest);
+            }
+        }
+        self.decision_order.push_back(trace_id);
+        self.decisions.insert(trace_id, keep);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use opentelemetry::KeyValue;
+    use std::time::UNIX_EPOCH;
+
+    fn span(trace_id: u128, span_id: u64, parent_span_id: u64, duration_ms: u64) -> CallSpan {
+        let start = UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        CallSpan {
+            name: format!("call {}", span_id),
+            trace_id,
+            span_id,
+            parent_span_id,
+            start_time: start,
+            end_time: start + Duration::from_millis(duration_ms),
+            attributes: vec![KeyValue::new("code.function", "handle")],
+            status: Status::Unset,
+            pid: 1,
+            binary_path: None,
+            service_name: None,
+        }
+    }
+
+    fn sampler(rules: Vec<TailSamplingRule>) -> TailSampler {
+        TailSampler::new(TailSamplingConfig {
+            enabled: true,
+            rules,
+            ..Default::default()
+        })
+    }
+
+    fn slow_rule() -> TailSamplingRule {
+        TailSamplingRule {
+            min_duration_ms: Some(100),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_trace_is_decided_when_root_completes() {
+        let mut sampler = sampler(vec![slow_rule()]);
+        let now = Instant::now();
+
+        // A slow child keeps the whole trace
+        assert!(sampler.add(span(1, 2, 1, 150), now).is_empty());
+        assert!(sampler.add(span(1, 3, 1, 5), now).is_empty());
+        let kept = sampler.add(span(1, 1, 0, 200), now);
+        assert_eq!(
+            kept.iter().map(|span| span.span_id).collect::<Vec<_>>(),
+            vec![2, 3, 1]
+        );
+
+        // A fast trace is dropped
+        assert!(sampler.add(span(2, 5, 4, 1), now).is_empty());
+        assert!(sampler.add(span(2, 4, 0, 2), now).is_empty());
+        assert_eq!(sampler.buffered_spans, 0);
+    }
+
+    #[test]
+    fn test_roots_sharing_a_trace_follow_its_decision() {
+        // Calls nested in an outermost aggregated call are roots of the
+        // trace drawn for it
+        let mut sampler = sampler(vec![slow_rule()]);
+        let now = Instant::now();
+
+        assert_eq!(sampler.add(span(1, 1, 0, 150), now).len(), 1);
+        assert_eq!(sampler.add(span(1, 2, 0, 5), now).len(), 1);
+
+        // The first root decides for the whole trace, even against a later
+        // slow one
+        assert!(sampler.add(span(2, 3, 0, 5), now).is_empty());
+        assert!(sampler.add(span(2, 4, 0, 150), now).is_empty());
+        assert_eq!(sampler.buffered_spans, 0);
+    }
+
+    #[test]
+    fn test_error_and_attribute_rules() {
+        let mut sampler = sampler(vec![
+            TailSamplingRule {
+                error: Some(true),
+                ..Default::default()
+            },
+            TailSamplingRule {
+                attributes: HashMap::from([("user_id".to_string(), "42".to_string())]),
+                ..Default::default()
+            },
+        ]);
+        let now = Instant::now();
+
+        let mut failed = span(1, 1, 0, 1);
+        failed.status = Status::error("negative return value");
+        assert_eq!(sampler.add(failed, now).len(), 1);
+
+        let mut vip = span(2, 2, 0, 1);
+        vip.attributes.push(KeyValue::new("user_id", 42));
+        assert_eq!(sampler.add(vip, now).len(), 1);
+
+        assert!(sampler.add(span(3, 3, 0, 1), now).is_empty());
+    }
+
+    #[test]
+    fn test_unfinished_trace_times_out() {
+        let mut sampler = sampler(vec![slow_rule()]);
+        let start = Instant::now();
+
+        assert!(sampler.add(span(1, 2, 1, 500), start).is_empty());
+        assert!(sampler.expire(start + Duration::from_secs(10)).is_empty());
+        assert_eq!(sampler.expire(start + Duration::from_secs(31)).len(), 1);
+
+        // The root returning later follows the decision
+        assert_eq!(sampler.add(span(1, 1, 0, 40_000), start).len(), 1);
+    }
+
+    #[test]
+    fn test_memory_budget_decides_oldest_trace_early() {
+        let mut sampler = TailSampler::new(TailSamplingConfig {
+            enabled: true,
+            rules: vec![slow_rule()],
+            max_buffered_spans: 2,
+            ..Default::default()
+        });
+        let now = Instant::now();
+
+        assert!(sampler.add(span(1, 11, 10, 500), now).is_empty());
+        assert!(sampler.add(span(2, 21, 20, 1), now).is_empty());
+        let kept = sampler.add(span(3, 31, 30, 1), now);
+        assert_eq!(kept.len(), 1);
+        assert_eq!(kept[0].trace_id, 1);
+        assert_eq!(sampler.buffered_spans, 2);
+    }
+
+    #[test]
+    fn test_probability_keeps_a_share_of_unmatched_traces() {
+        let mut sampler = TailSampler::new(TailSamplingConfig {
+            enabled: true,
+            probability: 0.5,
+            ..Default::default()
+        });
+        let now = Instant::now();
+
+        assert_eq!(sampler.add(span(1 << 62, 1, 0, 1), now).len(), 1);
+        assert!(sampler.add(span(u64::MAX as u128, 2, 0, 1), now).is_empty());
+    }
+
+    #[test]
+    fn test_invalid_config_is_rejected() {
+        for config in [
+            TailSamplingConfig {
+                probability: 2.0,
+                ..Default::default()
+            },
+            TailSamplingConfig {
+                rules: vec![TailSamplingRule::default()],
+                ..Default::default()
+            },
+        ] {
+            assert_eq!(
+                config.validate().unwrap_err().kind(),
+                io::ErrorKind::InvalidInput
+            );
+        }
+    }
+}
@@ -0,0 +1,215 @@
+use crate::debuginfo::DebugInfoConfig;
+use crate::manager::ManagerHandle;
+use crate::offset_tracker::{OffsetTracker, TrackedProcess};
+use crate::process::{self, ProcessConfig};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::io;
+use std::time::Duration;
+
+fn default_scan_interval_secs() -> u64 {
+    5
+}
+
+/// Periodic rescan of `/proc` for processes matching the `processes`
+/// entries, so processes started after the agent are instrumented too.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct WatchConfig {
+    #[serde(default)]
+    pub enabled: bool,
+    #[serde(default = "default_scan_interval_secs")]
+    pub scan_interval_secs: u64,
+}
+
+impl Default for WatchConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            scan_interval_secs: default_scan_interval_secs(),
+        }
+    }
+}
+
+impl WatchConfig {
+    pub fn validate(&self) -> io::Result<()> {
+        if self.scan_interval_secs == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "watch scan_interval_secs must be positive",
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// Processes that appeared or went away since the previous scan.
+#[derive(Debug, Default)]
+pub struct ProcessChanges {
+    pub started: Vec<(u32, TrackedProcess)>,
+    pub exited: Vec<u32>,
+}
+
+/// Tracks the processes selected by the `processes` entries across scans.
+/// Processes are identified by pid and start time, so a reused pid is seen
+/// as an exit followed by a start.
+pub struct ProcessWatcher {
+    rules: Vec<ProcessConfig>,
+    debug_info: DebugInfoConfig,
+    scan_interval: Duration,
+    /// Start time of each tracked process, in clock ticks since boot.
+    known: HashMap<u32, u64>,
+    /// Function ids of the probes attached to each tracked process.
+    probes: HashMap<u32, Vec<u64>>,
+}
+
+impl ProcessWatcher {
+    /// `tracked` are the processes already instrumented at startup, with
+    /// the function ids of their probes.
+    pub fn new(
+        config: &WatchConfig,
+        rules: Vec<ProcessConfig>,
+        debug_info: DebugInfoConfig,
+        tracked: HashMap<u32, Vec<u64>>,
+    ) -> Self {
+        let known = tracked
+            .keys()
+            .filter_map(|&pid| Some((pid, process::start_time(pid)?)))
+            .collect();
+        Self {
+            rules,
+            debug_info,
+            scan_interval: Duration::from_secs(config.scan_interval_secs),
+            known,
+            probes: tracked,
+        }
+    }
+
+    /// Scans every interval and attaches or removes probes through
+    /// `manager` until the manager stops.
+    pub async fn run(mut self, manager: ManagerHandle) -> anyhow::Result<()> {
+        let mut ticker = tokio::time::interval(self.scan_interval);
+        ticker.tick().await; // The first tick completes immediately
+
+        loop {
+            ticker.tick().await;
+            // Reading /proc and parsing ELF files blocks
+            let (watcher, changes) = tokio::task::spawn_blocking(move || {
+                let changes = self.scan();
+                (self, changes)
+            })
+            .await?;
+            self = watcher;
+
+            for pid in changes.exited {
+                println!("Process {} exited, removing its probes", pid);
+                for function_id in self.probes.remove(&pid).unwrap_or_default() {
+                    if let Err(err) = manager.unregister_probe(function_id).await {
+                        eprintln!("⚠️ Failed to remove probe {}: {}", function_id, err);
+                    }
+                }
+            }
+            for (pid, process) in changes.started {
+                for (object, functions) in process.objects {
+                    for function_info in functions.into_values() {
+                        let name = function_info.demangled_name.clone();
+                        match manager
+                            .register_probe(&object, function_info, Some(pid))
+                            .await
+                        {
+                            Ok(function_id) => {
+                                println!(
+                                    "🔍 Attached probe {} to {} in {} of new pid {}",
+                                    function_id, name, object, pid
+                                );
+                                self.probes.entry(pid).or_default().push(function_id);
+                            }
+                            Err(err) => {
+                                eprintln!("⚠️ Failed to attach to {} in pid {}: {}", name, pid, err)
+                            }
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    pub fn scan(&mut self) -> ProcessChanges {
+        let mut changes = ProcessChanges::default();
+        self.known.retain(|&pid, &mut started| {
+            let running = process::start_time(pid) == Some(started);
+            if !running {
+                changes.exited.push(pid);
+            }
+            running
+        });
+
+        for rule in &self.rules {
+            let pids = match rule.matching_pids() {
+                Ok(pids) => pids,
+                Err(err) => {
+                    eprintln!("⚠️ Failed to scan processes for {:?}: {}", rule, err);
+                    continue;
+                }
+            };
+            for pid in pids {
+                if self.known.contains_key(&pid) {
+                    continue;
+                }
+                // Gone already, or a configured pid that is not running
+                let Some(started) = process::start_time(pid) else {
+                    continue;
+                };
+                match OffsetTracker::track_process(pid, rule, &self.debug_info) {
+                    Ok(process) => {
+                        self.known.insert(pid, started);
+                        changes.started.push((pid, process));
+                    }
+                    Err(err) => eprintln!("⚠️ Failed to inspect pid {}: {}", pid, err),
+                }
+            }
+        }
+        changes
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_started_and_exited_processes() {
+        let rule: ProcessConfig =
+            serde_json::from_str(r#"{"comm": "^sleep$", "functions": ["nanosleep"]}"#).unwrap();
+        let mut watcher = ProcessWatcher::new(
+            &WatchConfig::default(),
+            vec![rule],
+            DebugInfoConfig::default(),
+            HashMap::new(),
+        );
+        let mut child = std::process::Command::new("sleep")
+            .arg("10")
+            .spawn()
+            .unwrap();
+        let pid = child.id();
+
+        // The child only takes its command name once it has exec'd
+        let mut started = Vec::new();
+        for _ in 0..100 {
+            std::thread::sleep(Duration::from_millis(10));
+            started.extend(watcher.scan().started);
+            if started.iter().any(|(started_pid, _)| *started_pid == pid) {
+                break;
+            }
+        }
+        assert!(started.iter().any(|(started_pid, _)| *started_pid == pid));
+        assert!(!watcher
+            .scan()
+            .started
+            .iter()
+            .any(|(started_pid, _)| *started_pid == pid));
+
+        child.kill().unwrap();
+        child.wait().unwrap();
+        assert!(watcher.scan().exited.contains(&pid));
+    }
+}